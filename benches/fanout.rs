@@ -0,0 +1,160 @@
+//! Broadcasts through a real room: one session posts to `ChatServer`, the
+//! `Room` actor fans the message out and every member receives it through
+//! its `Outbox`. The benchmark reports the time per broadcast and the bytes
+//! allocated for it. Rooms share one buffer between all recipients, so the
+//! allocated bytes hardly grow with the message length; a copy per
+//! recipient would allocate `len * members`.
+//!
+//! Run with `cargo bench --bench fanout`.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use app::{config::Config, server};
+use tokio::sync::Notify;
+
+/// Broadcasts per measurement
+const ROUNDS: usize = 200;
+
+/// Counts the bytes allocated by all threads
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Deliveries of the current broadcast
+#[derive(Default)]
+struct Progress {
+    received: AtomicUsize,
+    expected: AtomicUsize,
+    done: Notify,
+}
+
+/// Room member that only counts chat messages
+struct Member {
+    progress: Arc<Progress>,
+}
+
+impl Actor for Member {
+    type Context = Context<Self>;
+}
+
+impl Handler<server::Message> for Member {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Message, _: &mut Context<Self>) {
+        // joins and presence are not part of the broadcast
+        if msg.meta.is_none() {
+            return;
+        }
+
+        let progress = &self.progress;
+        if progress.received.fetch_add(1, Ordering::SeqCst) + 1
+            == progress.expected.load(Ordering::SeqCst)
+        {
+            progress.done.notify_one();
+        }
+    }
+}
+
+async fn join(
+    srv: &Addr<server::ChatServer>,
+    room: &str,
+    addr: Recipient<server::Message>,
+) -> usize {
+    let id = srv.send(server::Connect { addr }).await.unwrap();
+    srv.send(server::Join {
+        id,
+        name: room.to_owned(),
+    })
+    .await
+    .unwrap();
+    id
+}
+
+/// Time and allocated bytes per broadcast, every run has its own room
+async fn run(srv: &Addr<server::ChatServer>, members: usize, len: usize) -> (Duration, usize) {
+    let room = format!("bench-{members}-{len}");
+    let progress = Arc::new(Progress::default());
+    let addrs: Vec<_> = (0..=members)
+        .map(|_| {
+            Member {
+                progress: progress.clone(),
+            }
+            .start()
+        })
+        .collect();
+    let mut ids = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        ids.push(join(srv, &room, addr.recipient()).await);
+    }
+    let sender = ids[0];
+
+    // let the join announcements settle
+    actix::clock::sleep(Duration::from_millis(100)).await;
+
+    let text = "x".repeat(len);
+    let mut elapsed = Duration::ZERO;
+    let mut allocated = 0;
+    for _ in 0..ROUNDS {
+        progress.received.store(0, Ordering::SeqCst);
+        progress.expected.store(members, Ordering::SeqCst);
+        let msg = server::ClientMessage {
+            id: sender,
+            msg: text.as_str().into(),
+            room: room.clone(),
+        };
+
+        let bytes = ALLOCATED.load(Ordering::Relaxed);
+        let start = Instant::now();
+        srv.do_send(msg);
+        progress.done.notified().await;
+        elapsed += start.elapsed();
+        allocated += ALLOCATED.load(Ordering::Relaxed) - bytes;
+    }
+
+    for id in ids {
+        srv.do_send(server::Disconnect { id });
+    }
+    (elapsed / ROUNDS as u32, allocated / ROUNDS)
+}
+
+fn main() {
+    actix::System::new().block_on(async {
+        let mut config = Config::from_env();
+        config.snapshot = None;
+        config.cluster = None;
+        let srv = server::ChatServer::new(Arc::new(AtomicUsize::new(0)), &config).start();
+
+        for members in [10, 100, 1_000] {
+            for len in [64, 16_384] {
+                let (time, bytes) = run(&srv, members, len).await;
+                println!(
+                    "members={members:<5} len={len:<6} {time:>10.2?} per broadcast, \
+                     {bytes:>9} bytes allocated, copies would need {:>9}",
+                    len * members
+                );
+            }
+        }
+    });
+}
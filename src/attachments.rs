@@ -0,0 +1,245 @@
+//! File uploads and downloads of a room. Files are stored in
+//! `ATTACHMENT_DIR` under the sha256 of their content, so a file shared
+//! twice is stored once. Both routes need the token of a chat session in
+//! the room, see `auth`. Files are served with `nosniff`, browsers use the
+//! MIME type the uploader gave.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use actix::prelude::*;
+use actix_files::NamedFile;
+use actix_multipart::Multipart;
+use actix_web::{
+    error::{ErrorInternalServerError, ErrorNotFound},
+    http::header::HeaderValue,
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType},
+    mime, web, Error, HttpRequest, HttpResponse,
+};
+use futures_util::StreamExt as _;
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::{auth::authorize, server};
+
+/// Longest file name that is kept, longer names are cut
+const MAX_NAME_LEN: usize = 255;
+
+#[derive(Debug, Clone)]
+pub struct AttachmentConfig {
+    /// Where files are stored
+    pub dir: PathBuf,
+
+    /// Largest upload in bytes
+    pub max_size: usize,
+
+    /// Accepted MIME types, like `image/png`
+    pub types: Vec<String>,
+
+    /// How long a shared file is kept, forever when zero
+    pub ttl: Duration,
+}
+
+/// `POST /rooms/{name}/attachments`, store the `file` field of a multipart
+/// form and post a download link to the room
+pub async fn upload(
+    req: HttpRequest,
+    room: web::Path<String>,
+    mut form: Multipart,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<crate::config::Config>,
+) -> Result<HttpResponse, Error> {
+    let config = &config.attachments;
+    let room = room.into_inner();
+    let id = match authorize(&req, &room, &srv).await? {
+        Ok(id) => id,
+        Err(res) => return Ok(res),
+    };
+
+    // refuse large uploads before reading them
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok());
+    if length.is_some_and(|length| length > config.max_size + 4096) {
+        return Ok(too_large(config));
+    }
+
+    while let Some(field) = form.next().await {
+        let mut field = field?;
+        if field.name() != "file" {
+            continue;
+        }
+
+        let mime = match field.content_type() {
+            Some(mime) if config.types.iter().any(|ty| ty == mime.essence_str()) => {
+                mime.essence_str().to_owned()
+            }
+            _ => {
+                return Ok(HttpResponse::UnsupportedMediaType()
+                    .body(format!("accepted types: {}", config.types.join(", "))))
+            }
+        };
+        let name = file_name(field.content_disposition().get_filename());
+
+        // the file is written while it is hashed, an unfinished upload
+        // removes it
+        let dir = config.dir.clone();
+        let mut upload = web::block(move || Upload::create(&dir))
+            .await?
+            .map_err(ErrorInternalServerError)?;
+        let mut size = 0;
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = field.next().await {
+            let chunk = chunk?;
+            size += chunk.len();
+            if size > config.max_size {
+                return Ok(too_large(config));
+            }
+            hasher.update(&chunk);
+            upload = web::block(move || upload.write(&chunk).map(|()| upload))
+                .await?
+                .map_err(ErrorInternalServerError)?;
+        }
+
+        let attachment = server::Attachment {
+            hash: format!("{:x}", hasher.finalize()),
+            name,
+            mime,
+            size,
+            time: chrono::Utc::now(),
+        };
+
+        let path = config.dir.join(&attachment.hash);
+        web::block(move || upload.persist(&path))
+            .await?
+            .map_err(ErrorInternalServerError)?;
+
+        let url = attachment.url(&room);
+        srv.send(server::PostAttachment {
+            id,
+            room,
+            attachment: attachment.clone(),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+        return Ok(HttpResponse::Created()
+            .insert_header((header::LOCATION, url.clone()))
+            .json(serde_json::json!({ "url": url, "attachment": attachment })));
+    }
+
+    Ok(HttpResponse::BadRequest().body("file field is required"))
+}
+
+/// `GET /rooms/{name}/attachments/{hash}`, only for members of the room
+pub async fn download(
+    req: HttpRequest,
+    path: web::Path<(String, String)>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<crate::config::Config>,
+) -> Result<HttpResponse, Error> {
+    let (room, hash) = path.into_inner();
+    if let Err(res) = authorize(&req, &room, &srv).await? {
+        return Ok(res);
+    }
+
+    let attachment = srv
+        .send(server::GetAttachment { room, hash })
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("unknown attachment"))?;
+
+    let file = NamedFile::open_async(config.attachments.dir.join(&attachment.hash))
+        .await
+        .map_err(|_| ErrorNotFound("unknown attachment"))?
+        .set_content_type(
+            attachment
+                .mime
+                .parse()
+                .unwrap_or(mime::APPLICATION_OCTET_STREAM),
+        )
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(attachment.name)],
+        });
+
+    let mut res = file.into_response(&req);
+    res.headers_mut().insert(
+        header::X_CONTENT_TYPE_OPTIONS,
+        HeaderValue::from_static("nosniff"),
+    );
+    Ok(res)
+}
+
+fn too_large(config: &AttachmentConfig) -> HttpResponse {
+    HttpResponse::PayloadTooLarge().body(format!("files are limited to {} bytes", config.max_size))
+}
+
+/// Last path component of the uploaded name, without line breaks and
+/// other control characters
+fn file_name(name: Option<&str>) -> String {
+    let name: String = name
+        .and_then(|name| name.rsplit(['/', '\\']).next())
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect();
+    let name = Some(name.trim())
+        .filter(|name| !name.is_empty())
+        .unwrap_or("file");
+
+    let mut end = name.len().min(MAX_NAME_LEN);
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    name[..end].to_owned()
+}
+
+/// File written next to its final path and moved there when it is
+/// complete, readers never see a partial file. Dropped unfinished uploads
+/// are removed.
+struct Upload {
+    tmp: PathBuf,
+    file: Option<fs::File>,
+}
+
+impl Upload {
+    fn create(dir: &Path) -> io::Result<Upload> {
+        fs::create_dir_all(dir)?;
+        let tmp = dir.join(format!(".upload-{:016x}", rand::thread_rng().gen::<u64>()));
+        let file = fs::File::create(&tmp)?;
+        Ok(Upload {
+            tmp,
+            file: Some(file),
+        })
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.file {
+            Some(file) => file.write_all(data),
+            None => Ok(()),
+        }
+    }
+
+    /// Move the file to `path`, a file that was shared before is replaced
+    /// by the same content
+    fn persist(mut self, path: &Path) -> io::Result<()> {
+        if let Some(file) = self.file.take() {
+            file.sync_all()?;
+        }
+        fs::rename(&self.tmp, path)
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        // persisted uploads were moved away
+        let _ = fs::remove_file(&self.tmp);
+    }
+}
@@ -0,0 +1,44 @@
+//! HTTP routes of a room are for its members. The client authenticates
+//! with the token of its chat session from `/token` as
+//! `Authorization: Bearer <token>`, never in the query where it would end
+//! up in logs. Moderator routes need `Authorization: Bearer <MODERATOR_TOKEN>`.
+
+use actix::prelude::*;
+use actix_web::{error::ErrorInternalServerError, http::header, Error, HttpRequest, HttpResponse};
+
+use crate::{config::Config, server};
+
+fn bearer(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Request carries the moderator token, always false without one
+pub fn is_moderator(req: &HttpRequest, config: &Config) -> bool {
+    config.moderator_token.is_some() && bearer(req) == config.moderator_token.as_deref()
+}
+
+/// Session of the token when it is in `room`, otherwise the response
+pub async fn authorize(
+    req: &HttpRequest,
+    room: &str,
+    srv: &Addr<server::ChatServer>,
+) -> Result<Result<usize, HttpResponse>, Error> {
+    let Some(token) = bearer(req).map(str::to_owned) else {
+        return Ok(Err(
+            HttpResponse::Unauthorized().body("token required, see /token")
+        ));
+    };
+
+    let res = srv
+        .send(server::Authorize {
+            token,
+            room: room.to_owned(),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(res.map_err(|err| HttpResponse::Forbidden().body(err)))
+}
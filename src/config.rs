@@ -0,0 +1,127 @@
+//! Application settings. Values are read from environment variables, a
+//! `.env` file in the working directory is loaded first.
+
+use std::{env, fmt::Display, str::FromStr, time::Duration};
+
+use rand::Rng;
+
+use crate::{
+    attachments::AttachmentConfig,
+    server::{ClusterConfig, OutboxConfig, OverflowPolicy, SnapshotConfig},
+    session::BinaryMode,
+};
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Address of the HTTP server, `BIND_ADDR`
+    pub bind: String,
+
+    /// Address of the plain tcp listener, `TCP_ADDR`, empty to disable
+    pub tcp: Option<String>,
+
+    /// Address of the IRC listener, `IRC_ADDR`, disabled when unset
+    pub irc: Option<String>,
+
+    /// Per session outbound queue, `OUTBOX_CAPACITY` and `OUTBOX_POLICY`
+    /// (`drop-oldest`, `notify` or `disconnect`)
+    pub outbox: OutboxConfig,
+
+    /// What websocket sessions do with binary frames, `BINARY_FRAMES`
+    /// (`ignore`, `protobuf` or `relay`)
+    pub binary: BinaryMode,
+
+    /// Sessions without a message for `IDLE_TIMEOUT` seconds are idle
+    pub idle_timeout: Duration,
+
+    /// `/mod` with `MODERATOR_TOKEN` makes a session moderator of its room,
+    /// the token also unlocks the audit trail. Disabled when unset.
+    pub moderator_token: Option<String>,
+
+    /// Uploads are stored in `ATTACHMENT_DIR`, limited to
+    /// `ATTACHMENT_MAX_SIZE` bytes and the comma separated MIME types of
+    /// `ATTACHMENT_TYPES`, and removed after `ATTACHMENT_TTL` seconds
+    pub attachments: AttachmentConfig,
+
+    /// Rooms are saved to `SNAPSHOT_PATH` every `SNAPSHOT_INTERVAL` seconds
+    /// and restored on startup. Disabled when unset.
+    pub snapshot: Option<SnapshotConfig>,
+
+    /// Cluster mode is enabled when `CLUSTER_LISTEN` is set. `CLUSTER_PEERS`
+    /// is a comma separated list of the other nodes, `NODE_ID` defaults to
+    /// a random id.
+    pub cluster: Option<ClusterConfig>,
+}
+
+impl Config {
+    pub fn from_env() -> Config {
+        dotenv::dotenv().ok();
+
+        let cluster = env::var("CLUSTER_LISTEN").ok().map(|listen| ClusterConfig {
+            node: var("NODE_ID", rand::thread_rng().gen()),
+            listen,
+            peers: env::var("CLUSTER_PEERS")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|peer| !peer.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        });
+
+        Config {
+            bind: var("BIND_ADDR", "0.0.0.0:8080".to_owned()),
+            tcp: Some(var("TCP_ADDR", "0.0.0.0:12345".to_owned())).filter(|addr| !addr.is_empty()),
+            irc: env::var("IRC_ADDR").ok().filter(|addr| !addr.is_empty()),
+            outbox: OutboxConfig {
+                capacity: var("OUTBOX_CAPACITY", 256),
+                policy: var("OUTBOX_POLICY", OverflowPolicy::Notify),
+            },
+            binary: var("BINARY_FRAMES", BinaryMode::Ignore),
+            idle_timeout: Duration::from_secs(var("IDLE_TIMEOUT", 300)),
+            moderator_token: env::var("MODERATOR_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            attachments: AttachmentConfig {
+                dir: var("ATTACHMENT_DIR", "./attachments".into()),
+                max_size: var("ATTACHMENT_MAX_SIZE", 10 * 1024 * 1024),
+                types: var(
+                    "ATTACHMENT_TYPES",
+                    "image/png,image/jpeg,image/gif,image/webp,application/pdf,text/plain"
+                        .to_owned(),
+                )
+                .split(',')
+                .map(str::trim)
+                .filter(|ty| !ty.is_empty())
+                .map(str::to_owned)
+                .collect(),
+                ttl: Duration::from_secs(var("ATTACHMENT_TTL", 7 * 24 * 60 * 60)),
+            },
+            snapshot: env::var("SNAPSHOT_PATH")
+                .ok()
+                .filter(|path| !path.is_empty())
+                .map(|path| SnapshotConfig {
+                    path: path.into(),
+                    interval: Duration::from_secs(var("SNAPSHOT_INTERVAL", 60)),
+                }),
+            cluster,
+        }
+    }
+}
+
+/// Read and parse variable, fall back to default when it is missing or invalid
+fn var<T>(key: &str, default: T) -> T
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match env::var(key) {
+        Ok(value) => match value.parse() {
+            Ok(value) => value,
+            Err(err) => {
+                log::warn!("invalid {key}: {err}, using default");
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
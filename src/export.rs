@@ -0,0 +1,398 @@
+//! Export of a room history for moderators. The history is fetched from
+//! the room page by page and every page is written to the response as soon
+//! as it arrives, so an export never holds the whole room in memory.
+//! Earlier versions of edited messages come from the audit trail, as far
+//! back as it goes.
+
+use std::{collections::HashMap, fmt::Write, str::FromStr};
+
+use actix::prelude::*;
+use actix_web::{
+    error::ErrorInternalServerError,
+    http::header::{self, ContentDisposition, DispositionParam, DispositionType},
+    web, Error, HttpRequest, HttpResponse,
+};
+use bytes::Bytes;
+use chrono::{DateTime, SecondsFormat, Utc};
+use futures_util::stream;
+use serde::{Deserialize, Serialize};
+
+use crate::{auth, config::Config, server};
+
+/// How many messages are fetched from the room at once
+const EXPORT_PAGE: usize = 50;
+
+/// Text of a message before an edit
+#[derive(Debug, Serialize)]
+struct Version {
+    text: String,
+
+    /// When the text was replaced
+    #[serde(rename = "replaced")]
+    time: DateTime<Utc>,
+}
+
+/// Stored message with its earlier versions, oldest first
+#[derive(Serialize)]
+struct ExportedMessage<'a> {
+    #[serde(flatten)]
+    message: &'a server::StoredMessage,
+
+    #[serde(skip_serializing_if = "<[_]>::is_empty")]
+    versions: &'a [Version],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    Html,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Format, String> {
+        match value {
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "html" => Ok(Format::Html),
+            _ => Err(format!("unknown format {value:?}, use json, csv or html")),
+        }
+    }
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Html => "text/html; charset=utf-8",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            Format::Csv => "csv",
+            Format::Html => "html",
+        }
+    }
+
+    fn header(self, out: &mut String, room: &str) {
+        match self {
+            Format::Json => out.push('['),
+            Format::Csv => out.push_str("seq,id,time,author,text,edited,thread,versions\r\n"),
+            Format::Html => {
+                let room = html_escape(room);
+                write!(
+                    out,
+                    "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\" />\n\
+                     <title>{room}</title>\n</head>\n<body>\n<h1>{room}</h1>\n<table>\n\
+                     <tr><th>seq</th><th>time</th><th>author</th><th>message</th><th>edited</th>\
+                     <th>thread</th><th>earlier versions</th></tr>\n"
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    /// `first` is the first message of the export, `versions` are its
+    /// earlier texts
+    fn row(
+        self,
+        out: &mut String,
+        stored: &server::StoredMessage,
+        versions: &[Version],
+        first: bool,
+    ) {
+        let edited = stored.meta.edited.map(time).unwrap_or_default();
+        let thread = stored.meta.thread.map(|id| id.to_string());
+        match self {
+            Format::Json => {
+                if !first {
+                    out.push(',');
+                }
+                out.push('\n');
+                let exported = ExportedMessage {
+                    message: stored,
+                    versions,
+                };
+                out.push_str(&serde_json::to_string(&exported).unwrap());
+            }
+            Format::Csv => {
+                let versions: Vec<&str> = versions.iter().map(|version| &*version.text).collect();
+                let fields: [&str; 8] = [
+                    &stored.meta.seq.to_string(),
+                    &stored.meta.id.to_string(),
+                    &time(stored.meta.time),
+                    &stored.name,
+                    &*stored.text,
+                    &edited,
+                    thread.as_deref().unwrap_or_default(),
+                    &versions.join("\n"),
+                ];
+                for (i, field) in fields.into_iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    csv_field(out, field);
+                }
+                out.push_str("\r\n");
+            }
+            Format::Html => {
+                let versions: Vec<String> = versions
+                    .iter()
+                    .map(|version| html_escape(&version.text))
+                    .collect();
+                writeln!(
+                    out,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+                    stored.meta.seq,
+                    time(stored.meta.time),
+                    html_escape(&stored.name),
+                    html_escape(&stored.text),
+                    edited,
+                    thread.as_deref().unwrap_or_default(),
+                    versions.join("<br />"),
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    fn footer(self, out: &mut String) {
+        match self {
+            Format::Json => out.push_str("\n]\n"),
+            Format::Csv => (),
+            Format::Html => out.push_str("</table>\n</body>\n</html>\n"),
+        }
+    }
+}
+
+fn time(time: DateTime<Utc>) -> String {
+    time.to_rfc3339_opts(SecondsFormat::Millis, true)
+}
+
+/// Quote fields with separators, quotes or line breaks. Fields that
+/// spreadsheets would take as formulas start with `'`.
+fn csv_field(out: &mut String, field: &str) {
+    let formula = field.starts_with(['=', '+', '-', '@', '\t', '\r']);
+    let field = if formula {
+        format!("'{field}")
+    } else {
+        field.to_owned()
+    };
+
+    if field.contains([',', '"', '\r', '\n']) {
+        out.push('"');
+        out.push_str(&field.replace('"', "\"\""));
+        out.push('"');
+    } else {
+        out.push_str(&field);
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    format: Option<String>,
+
+    /// Messages sent at or after, a day or RFC 3339 time
+    from: Option<String>,
+
+    /// Messages sent before, a day includes that day
+    to: Option<String>,
+}
+
+/// Where an export is, it moves on with every page
+struct Export {
+    srv: Addr<server::ChatServer>,
+    room: String,
+    format: Format,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+
+    /// Next page, the first one is fetched before the response starts
+    page: Option<server::HistoryPage>,
+
+    /// Earlier texts of edited messages by message id
+    versions: HashMap<u64, Vec<Version>>,
+
+    /// Sequence number of the last exported page
+    since: u64,
+    started: bool,
+    written: usize,
+    done: bool,
+}
+
+impl Export {
+    /// Next part of the response, `None` after the footer
+    async fn next(mut self) -> Option<(Result<Bytes, Error>, Export)> {
+        if self.done {
+            return None;
+        }
+
+        let mut out = String::new();
+        if !self.started {
+            self.format.header(&mut out, &self.room);
+            self.started = true;
+        }
+
+        let page = match self.page.take() {
+            Some(page) => Some(page),
+            None => {
+                let res = self
+                    .srv
+                    .send(server::GetHistory {
+                        room: self.room.clone(),
+                        since: self.since,
+                        limit: EXPORT_PAGE,
+                    })
+                    .await;
+                match res {
+                    Ok(page) => page,
+                    Err(err) => {
+                        self.done = true;
+                        return Some((Err(ErrorInternalServerError(err)), self));
+                    }
+                }
+            }
+        };
+
+        // a room that is gone has no more pages
+        let more = match page {
+            Some(page) => {
+                for stored in page.messages.iter().filter(|stored| {
+                    self.from.is_none_or(|from| stored.meta.time >= from)
+                        && self.to.is_none_or(|to| stored.meta.time < to)
+                }) {
+                    let versions = self
+                        .versions
+                        .get(&stored.meta.id)
+                        .map_or(&[][..], Vec::as_slice);
+                    self.format
+                        .row(&mut out, stored, versions, self.written == 0);
+                    self.written += 1;
+                }
+                if let Some(last) = page.messages.last() {
+                    self.since = last.meta.seq;
+                }
+                page.more
+            }
+            None => false,
+        };
+
+        if !more {
+            self.format.footer(&mut out);
+            self.done = true;
+        }
+        Some((Ok(Bytes::from(out)), self))
+    }
+}
+
+/// `GET /api/rooms/{name}/export?format=json|csv|html&from=&to=`, needs
+/// `Authorization: Bearer <MODERATOR_TOKEN>`
+pub async fn export(
+    req: HttpRequest,
+    room: web::Path<String>,
+    params: web::Query<ExportParams>,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<Config>,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_moderator(&req, &config) {
+        return Ok(HttpResponse::Forbidden().body("moderator token required"));
+    }
+
+    let params = params.into_inner();
+    let parsed = (|| {
+        let format = params
+            .format
+            .as_deref()
+            .unwrap_or("json")
+            .parse::<Format>()?;
+        let from = params
+            .from
+            .as_deref()
+            .map(|from| server::parse_time(from, false))
+            .transpose()?;
+        let to = params
+            .to
+            .as_deref()
+            .map(|to| server::parse_time(to, true))
+            .transpose()?;
+        Ok::<_, String>((format, from, to))
+    })();
+    let (format, from, to) = match parsed {
+        Ok(parsed) => parsed,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+    };
+
+    let room = room.into_inner();
+    let first = srv
+        .send(server::GetHistory {
+            room: room.clone(),
+            since: 0,
+            limit: EXPORT_PAGE,
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let Some(first) = first else {
+        return Ok(HttpResponse::NotFound().body("room does not exist"));
+    };
+
+    let audit = srv
+        .send(server::GetAudit)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let mut versions: HashMap<u64, Vec<Version>> = HashMap::new();
+    for entry in audit
+        .into_iter()
+        .filter(|entry| entry.room == room && entry.action == server::AuditAction::Edit)
+    {
+        versions.entry(entry.message_id).or_default().push(Version {
+            text: entry.text,
+            time: entry.time,
+        });
+    }
+
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!(
+            "{room}.{}",
+            format.extension()
+        ))],
+    };
+    let export = Export {
+        srv: srv.get_ref().clone(),
+        room,
+        format,
+        from,
+        to,
+        page: Some(first),
+        versions,
+        since: 0,
+        started: false,
+        written: 0,
+        done: false,
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::CONTENT_DISPOSITION, disposition))
+        .streaming(stream::unfold(export, Export::next)))
+}
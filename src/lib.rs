@@ -0,0 +1,9 @@
+//! Websocket chat server. The binary wires these modules into HTTP routes,
+//! benches use them directly.
+
+pub mod attachments;
+pub mod auth;
+pub mod config;
+pub mod export;
+pub mod server;
+pub mod session;
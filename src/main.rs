@@ -1,4 +1,5 @@
 use std::{
+    fmt::Write,
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -7,15 +8,15 @@ use std::{
 };
 
 use actix::*;
-use actix_files::{Files, NamedFile};
+use actix_files::NamedFile;
 use actix_web::{
-    middleware::Logger, web, App, Error, HttpRequest, HttpResponse, HttpServer, Responder,
+    error::ErrorInternalServerError, middleware::Logger, web, App, Error, HttpRequest,
+    HttpResponse, HttpServer, Responder,
 };
 use actix_web_actors::ws;
-use futures_util::StreamExt;
-
-mod server;
-mod session;
+use app::{attachments, auth, config, export, server, session};
+use futures_util::future;
+use serde::Deserialize;
 
 async fn index() -> impl Responder {
     NamedFile::open_async("./static/index.html").await.unwrap()
@@ -26,6 +27,7 @@ async fn chat_route(
     req: HttpRequest,
     stream: web::Payload,
     srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<config::Config>,
 ) -> Result<HttpResponse, Error> {
     // while let Some(item) = stream.next().await {
     //     let mut bytes = web::BytesMut::new();
@@ -38,78 +40,338 @@ async fn chat_route(
     println!("srv: {srv:?}");
     println!("srv: {:?}", Instant::now());
 
+    // clients without subprotocol speak the text protocol
+    let codec = match session::Codec::negotiate(&req) {
+        Ok(codec) => codec,
+        Err(offered) => {
+            log::debug!("unsupported websocket subprotocols: {offered}");
+            return Ok(HttpResponse::BadRequest().body(format!(
+                "unsupported subprotocol, expected one of: {}",
+                session::Codec::PROTOCOLS.join(", ")
+            )));
+        }
+    };
+
+    let session = session::WsChatSession {
+        core: session::SessionCore::new(srv.get_ref().clone()),
+        room: "main".to_owned(),
+        name: None,
+        binary: config.binary,
+        codec: codec.unwrap_or(session::Codec::Text),
+    };
+
+    match codec {
+        Some(codec) => ws::WsResponseBuilder::new(session, &req, stream)
+            .protocols(&[codec.protocol()])
+            .start(),
+        None => ws::start(session, &req, stream),
+    }
+}
 
+/// Entry point for our game websocket route
+async fn game_route(
+    req: HttpRequest,
+    stream: web::Payload,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
     ws::start(
-        session::WsChatSession {
-            id: 0,
-            hb: Instant::now(),
-            room: "main".to_owned(),
-            name: None,
-            addr: srv.get_ref().clone(),
+        session::WsGameSession {
+            core: session::SessionCore::new(srv.get_ref().clone()),
+            room_name: "main".to_owned(),
+            cli_name: None,
         },
         &req,
         stream,
     )
 }
 
-/// Entry point for our websocket route
-// async fn game_route(
-//     req: HttpRequest,
-//     stream: web::Payload,
-//     srv: web::Data<Addr<server::ChatServer>>,
-// ) -> Result<HttpResponse, Error> {
-//     ws::start(
-//         session::WsChatSession {
-//             id: 0,
-//             hb: Instant::now(),
-//             room: "main".to_owned(),
-//             name: None,
-//             addr: srv.get_ref().clone(),
-//         },
-//         &req,
-//         stream,
-//     )
-// }
-
 /// Displays state
 async fn get_count(count: web::Data<AtomicUsize>) -> impl Responder {
     let current_count = count.load(Ordering::SeqCst);
     format!("Visitors: {current_count}")
 }
 
-/// Displays state
+/// Outbox state of every session in Prometheus text format, sessions that
+/// fall behind have queued or dropped messages
+async fn get_metrics(srv: web::Data<Addr<server::ChatServer>>) -> Result<HttpResponse, Error> {
+    let metrics = srv
+        .send(server::Metrics)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    let mut body = String::new();
+    writeln!(body, "chat_sessions {}", metrics.sessions.len()).unwrap();
+    writeln!(body, "chat_outbox_overflows_total {}", metrics.overflows).unwrap();
+    for s in metrics.sessions {
+        let labels = format!(
+            "session=\"{}\",room=\"{}\"",
+            s.id,
+            s.room.as_deref().unwrap_or_default()
+        );
+        writeln!(body, "chat_outbox_queued{{{labels}}} {}", s.outbox.queued).unwrap();
+        writeln!(
+            body,
+            "chat_outbox_dropped_total{{{labels}}} {}",
+            s.outbox.dropped
+        )
+        .unwrap();
+        if let Some(rtt) = s.rtt {
+            writeln!(
+                body,
+                "chat_session_rtt_seconds{{{labels}}} {}",
+                rtt.as_secs_f64()
+            )
+            .unwrap();
+        }
+    }
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
+}
+
+/// Presence of every connected session
+async fn get_presence(srv: web::Data<Addr<server::ChatServer>>) -> Result<HttpResponse, Error> {
+    let users = srv
+        .send(server::GetPresence { name: None })
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// Presence of one user, offline users are known until the name is taken
+/// again
+async fn get_user_presence(
+    name: web::Path<String>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let users = srv
+        .send(server::GetPresence {
+            name: Some(name.into_inner()),
+        })
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    if users.is_empty() {
+        return Ok(HttpResponse::NotFound().body("unknown user"));
+    }
+    Ok(HttpResponse::Ok().json(users))
+}
+
+/// Write a snapshot of the rooms now, needs
+/// `Authorization: Bearer <MODERATOR_TOKEN>`
+async fn take_snapshot(
+    req: HttpRequest,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_moderator(&req, &config) {
+        return Ok(HttpResponse::Forbidden().body("moderator token required"));
+    }
+
+    match srv
+        .send(server::TakeSnapshot)
+        .await
+        .map_err(ErrorInternalServerError)?
+    {
+        Ok(info) => Ok(HttpResponse::Ok().json(info)),
+        Err(err) => Ok(HttpResponse::ServiceUnavailable().body(err)),
+    }
+}
+
+/// Edited and deleted messages, needs `Authorization: Bearer <MODERATOR_TOKEN>`
+async fn get_audit(
+    req: HttpRequest,
+    srv: web::Data<Addr<server::ChatServer>>,
+    config: web::Data<config::Config>,
+) -> Result<HttpResponse, Error> {
+    if !auth::is_moderator(&req, &config) {
+        return Ok(HttpResponse::Forbidden().body("moderator token required"));
+    }
+
+    let entries = srv
+        .send(server::GetAudit)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    /// Words, may hold the filters of `/search`
+    #[serde(default)]
+    q: String,
+    author: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<usize>,
+}
+
+impl SearchParams {
+    fn query(self) -> Result<server::SearchQuery, String> {
+        let mut query = server::SearchQuery::parse(&self.q)?;
+        if let Some(author) = self.author {
+            query.author = Some(author);
+        }
+        if let Some(from) = self.from {
+            query.from = Some(server::parse_time(&from, false)?);
+        }
+        if let Some(to) = self.to {
+            query.to = Some(server::parse_time(&to, true)?);
+        }
+        if let Some(limit) = self.limit {
+            query.set_limit(limit);
+        }
+
+        if query.is_empty() {
+            return Err("q is required".to_owned());
+        }
+        Ok(query)
+    }
+}
+
+/// Ranked search of the stored messages of a room, for its members. Only
+/// the last 100 messages are stored, older ones are never found.
+async fn search_room(
+    req: HttpRequest,
+    room: web::Path<String>,
+    params: web::Query<SearchParams>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<HttpResponse, Error> {
+    let room = room.into_inner();
+    let params = params.into_inner();
+    if let Err(res) = auth::authorize(&req, &room, &srv).await? {
+        return Ok(res);
+    }
+
+    let query = match params.query() {
+        Ok(query) => query,
+        Err(err) => return Ok(HttpResponse::BadRequest().body(err)),
+    };
+
+    let hits = srv
+        .send(server::Search { room, query })
+        .await
+        .map_err(ErrorInternalServerError)?
+        .unwrap_or_default();
+
+    Ok(HttpResponse::Ok().json(hits))
+}
+
+// Displays state
 // async fn get_count() -> impl Responder {
 //     let current_count = count.load(Ordering::SeqCst);
 //     return "hoge"
 // }
 
+/// Resolves on Ctrl-C or SIGTERM, after the last snapshot is written. The
+/// rooms are stopped with the server, so the snapshot can not wait until
+/// the server is down.
+async fn shutdown(srv: Addr<server::ChatServer>, snapshot: bool) {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+
+        let mut term = signal(SignalKind::terminate()).expect("can not listen for SIGTERM");
+        let ctrl_c = Box::pin(actix_web::rt::signal::ctrl_c());
+        future::select(ctrl_c, Box::pin(term.recv())).await;
+    }
+    #[cfg(not(unix))]
+    let _ = actix_web::rt::signal::ctrl_c().await;
+
+    // keep what was said since the last snapshot
+    if snapshot {
+        match srv.send(server::TakeSnapshot).await {
+            Ok(Ok(info)) => log::info!("snapshot of {} rooms written to {}", info.rooms, info.path),
+            Ok(Err(err)) => log::error!("can not write snapshot: {err}"),
+            Err(err) => log::error!("can not write snapshot: {err}"),
+        }
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("info"));
 
+    let config = config::Config::from_env();
+
     // set up applications state
     // keep a count of the number of visitors
     let app_state = Arc::new(AtomicUsize::new(0));
 
     // start chat server actor
-    let server = server::ChatServer::new(app_state.clone()).start();
+    let server = server::ChatServer::new(app_state.clone(), &config).start();
+
+    // long polling sessions are shared by all workers
+    let poll_sessions = web::Data::new(session::poll::PollSessions::default());
+
+    // settings used by the routes
+    let app_config = web::Data::new(config.clone());
+
+    // tcp listener runs in separate thread
+    if let Some(addr) = config.tcp.clone() {
+        let server = server.clone();
+        Arbiter::new().spawn(async move {
+            if let Err(err) = session::tcp::serve(&addr, server).await {
+                log::error!("tcp listener on {addr} failed: {err}");
+            }
+        });
+    }
+
+    // so does the irc listener
+    if let Some(addr) = config.irc.clone() {
+        let server = server.clone();
+        Arbiter::new().spawn(async move {
+            if let Err(err) = session::irc::serve(&addr, server).await {
+                log::error!("irc listener on {addr} failed: {err}");
+            }
+        });
+    }
 
-    log::info!("starting HTTP server at http://localhost:8080");
+    log::info!("starting HTTP server at http://{}", config.bind);
 
+    let chat_server = server.clone();
     HttpServer::new(move || {
         App::new()
             .app_data(web::Data::from(app_state.clone()))
             .app_data(web::Data::new(server.clone()))
+            .app_data(poll_sessions.clone())
+            .app_data(app_config.clone())
             .service(web::resource("/").to(index))
             // .route("/test", web::get().to(get_access))
             .route("/count", web::get().to(get_count))
+            .route("/metrics", web::get().to(get_metrics))
+            .route("/api/presence", web::get().to(get_presence))
+            .route("/api/presence/{name}", web::get().to(get_user_presence))
+            .route("/api/audit", web::get().to(get_audit))
+            .route("/api/snapshot", web::post().to(take_snapshot))
+            .route("/api/rooms/{name}/search", web::get().to(search_room))
+            .route("/api/rooms/{name}/export", web::get().to(export::export))
             .route("/ws", web::get().to(chat_route))
-            // .route("/game", web::get().to(chat_route))
+            .route(
+                "/rooms/{name}/events",
+                web::get().to(session::sse::room_events),
+            )
+            .route(
+                "/rooms/{name}/attachments",
+                web::post().to(attachments::upload),
+            )
+            .route(
+                "/rooms/{name}/attachments/{hash}",
+                web::get().to(attachments::download),
+            )
+            .route("/game", web::get().to(game_route))
+            .route("/poll/connect", web::post().to(session::poll::connect))
+            .route("/poll/{session}", web::get().to(session::poll::poll))
+            .route("/poll/{session}/send", web::post().to(session::poll::send))
             // .service(Files::new("/static", "./static"))
             .wrap(Logger::default())
     })
     .workers(2)
-    .bind(("0.0.0.0", 8080))?
+    .shutdown_signal(shutdown(chat_server, config.snapshot.is_some()))
+    .bind(&config.bind)?
     .run()
     .await
 }
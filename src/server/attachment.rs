@@ -0,0 +1,60 @@
+//! Files shared in rooms. The files are stored on disk by the HTTP routes,
+//! `ChatServer` knows which room may download which file and removes the
+//! files once no room shares them anymore.
+
+use std::{
+    fs,
+    path::PathBuf,
+    time::{Duration, SystemTime},
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How often expired attachments are removed
+pub const ATTACHMENT_CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Uploaded file, stored under the sha256 of its content
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    /// Hex sha256 of the content
+    pub hash: String,
+
+    /// File name given by the uploader
+    pub name: String,
+
+    pub mime: String,
+
+    /// Size in bytes
+    pub size: usize,
+
+    /// When the file was shared
+    #[serde(default = "Utc::now")]
+    pub time: DateTime<Utc>,
+}
+
+impl Attachment {
+    /// Download link in `room`
+    pub fn url(&self, room: &str) -> String {
+        format!("/rooms/{room}/attachments/{}", self.hash)
+    }
+
+    /// File name for the room text, `@` would mention someone
+    pub fn label(&self) -> String {
+        self.name.replace('@', "_")
+    }
+}
+
+/// Remove the files of `hashes` from `dir`, files written after `before`
+/// were uploaded again and stay
+pub fn remove_files(dir: PathBuf, hashes: Vec<String>, before: SystemTime) {
+    for hash in hashes {
+        let path = dir.join(&hash);
+        let modified = fs::metadata(&path).and_then(|meta| meta.modified());
+        if modified.is_ok_and(|modified| modified < before) {
+            if let Err(err) = fs::remove_file(&path) {
+                log::warn!("can not remove attachment {hash}: {err}");
+            }
+        }
+    }
+}
@@ -0,0 +1,355 @@
+//! Cluster mode. Several `ChatServer` nodes exchange room messages and
+//! presence over a full TCP mesh: every node connects to all of its peers
+//! and writes events as JSON lines, and reads the events of its peers on
+//! its own listener. Events are never forwarded, so every node has to list
+//! all other nodes in its peers. Relayed binary data travels as base64.
+
+use std::{collections::HashSet, io, time::Duration};
+
+use actix::prelude::*;
+use actix_codec::LinesCodec;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bytestring::ByteString;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::mpsc,
+};
+use tokio_util::codec::FramedRead;
+
+use super::ChatServer;
+
+/// How long to wait before reconnecting to a peer
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Events waiting to be written to a peer, further events are dropped
+const PEER_BUFFER: usize = 1024;
+
+/// Cluster settings of this node
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// Unique id of this node
+    pub node: u64,
+
+    /// Address peers connect to
+    pub listen: String,
+
+    /// Addresses of all other nodes
+    pub peers: Vec<String>,
+}
+
+/// Event exchanged between nodes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Event {
+    /// Node the event comes from
+    pub node: u64,
+
+    /// Room name
+    pub room: String,
+
+    pub kind: EventKind,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    /// Peer message sent to the room, it keeps its id and time on every
+    /// node
+    Message {
+        id: u64,
+        time: DateTime<Utc>,
+        #[serde(default)]
+        name: String,
+        text: String,
+        #[serde(default)]
+        thread: Option<u64>,
+    },
+
+    /// Opaque data relayed to the room
+    Data {
+        #[serde(
+            serialize_with = "serialize_data",
+            deserialize_with = "deserialize_data"
+        )]
+        data: Vec<u8>,
+    },
+
+    /// Session on the node joined the room
+    Enter { session: usize },
+
+    /// Session on the node left the room
+    Leave { session: usize },
+}
+
+fn serialize_data<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&STANDARD.encode(data))
+}
+
+fn deserialize_data<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let data = String::deserialize(deserializer)?;
+    STANDARD.decode(data).map_err(serde::de::Error::custom)
+}
+
+/// Event received from another node
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Remote(pub Event);
+
+/// Connection from a node was lost, its sessions left all rooms
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct NodeDown {
+    pub node: u64,
+}
+
+/// Connected to a peer, it has to learn about local sessions
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct PeerConnected(pub Addr<PeerLink>);
+
+/// Write serialized event to a peer
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Forward(pub ByteString);
+
+/// Outgoing side of the mesh, owned by `ChatServer`
+#[derive(Debug)]
+pub struct Cluster {
+    node: u64,
+    peers: Vec<Addr<PeerLink>>,
+}
+
+impl Cluster {
+    /// Start listener and connect to all peers
+    pub fn start(config: &ClusterConfig, server: Addr<ChatServer>) -> Cluster {
+        let listen = config.listen.clone();
+        let srv = server.clone();
+        actix::spawn(async move {
+            if let Err(err) = listen_peers(&listen, srv).await {
+                log::error!("cluster listener on {listen} failed: {err}");
+            }
+        });
+
+        let peers = config
+            .peers
+            .iter()
+            .map(|addr| {
+                PeerLink {
+                    addr: addr.clone(),
+                    server: server.clone(),
+                    writer: None,
+                    watch: None,
+                }
+                .start()
+            })
+            .collect();
+
+        log::info!(
+            "cluster node {} listening on {}",
+            config.node,
+            config.listen
+        );
+
+        Cluster {
+            node: config.node,
+            peers,
+        }
+    }
+
+    /// Id of this node
+    pub fn node(&self) -> u64 {
+        self.node
+    }
+
+    /// Send event to all peers
+    pub fn publish(&self, room: &str, kind: EventKind) {
+        for peer in &self.peers {
+            self.send(peer, room, kind.clone());
+        }
+    }
+
+    /// Send event to one peer
+    pub fn send(&self, peer: &Addr<PeerLink>, room: &str, kind: EventKind) {
+        let event = Event {
+            node: self.node,
+            room: room.to_owned(),
+            kind,
+        };
+
+        match serde_json::to_string(&event) {
+            Ok(line) => peer.do_send(Forward(line.into())),
+            Err(err) => log::error!("can not encode cluster event: {err}"),
+        }
+    }
+}
+
+/// Accept connections from other nodes
+async fn listen_peers(addr: &str, server: Addr<ChatServer>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        log::info!("cluster peer connected from {peer}");
+
+        let server = server.clone();
+        PeerSession::create(move |ctx| {
+            ctx.add_stream(FramedRead::new(stream, LinesCodec::default()));
+            PeerSession {
+                server,
+                nodes: HashSet::new(),
+            }
+        });
+    }
+}
+
+/// Connection to a peer, events of this node are written to it.
+/// Reconnects when the connection is lost, events are dropped meanwhile.
+pub struct PeerLink {
+    addr: String,
+    server: Addr<ChatServer>,
+
+    /// Lines for the writer task of the current connection
+    writer: Option<mpsc::Sender<ByteString>>,
+
+    /// Future waiting for the peer to close the current connection
+    watch: Option<SpawnHandle>,
+}
+
+impl PeerLink {
+    fn connect(&mut self, ctx: &mut Context<Self>) {
+        TcpStream::connect(self.addr.clone())
+            .into_actor(self)
+            .map(|res, act, ctx| match res {
+                Ok(stream) => {
+                    log::info!("connected to cluster peer {}", act.addr);
+                    let (read, write) = stream.into_split();
+                    let (tx, rx) = mpsc::channel(PEER_BUFFER);
+                    actix::spawn(write_lines(write, rx));
+                    act.writer = Some(tx);
+
+                    // peers never write on this connection, so reading only
+                    // ends when the peer is gone
+                    let watch = ctx.spawn(wait_closed(read).into_actor(act).map(|_, act, ctx| {
+                        log::warn!("lost cluster peer {}", act.addr);
+                        act.watch = None;
+                        act.reconnect(ctx);
+                    }));
+                    act.watch = Some(watch);
+                    act.server.do_send(PeerConnected(ctx.address()));
+                }
+                Err(err) => {
+                    log::debug!("can not connect to cluster peer {}: {err}", act.addr);
+                    act.reconnect(ctx);
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn reconnect(&mut self, ctx: &mut Context<Self>) {
+        // dropping the sender ends the writer task
+        self.writer = None;
+        if let Some(watch) = self.watch.take() {
+            ctx.cancel_future(watch);
+        }
+        ctx.run_later(RECONNECT_INTERVAL, |act, ctx| act.connect(ctx));
+    }
+}
+
+impl Actor for PeerLink {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.connect(ctx);
+    }
+}
+
+/// Handler for Forward message.
+impl Handler<Forward> for PeerLink {
+    type Result = ();
+
+    fn handle(&mut self, msg: Forward, ctx: &mut Self::Context) {
+        let Some(writer) = &self.writer else {
+            return;
+        };
+
+        match writer.try_send(msg.0) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                log::debug!("cluster peer {} is behind, event dropped", self.addr);
+            }
+            // writer task stopped on a write error
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                log::warn!("lost cluster peer {}", self.addr);
+                self.reconnect(ctx);
+            }
+        }
+    }
+}
+
+/// Write lines to a peer until the link drops the sender or a write fails
+async fn write_lines(mut write: OwnedWriteHalf, mut rx: mpsc::Receiver<ByteString>) {
+    while let Some(line) = rx.recv().await {
+        let res = async {
+            write.write_all(line.as_bytes()).await?;
+            write.write_all(b"\n").await
+        }
+        .await;
+
+        if let Err(err) = res {
+            log::warn!("can not write to cluster peer: {err}");
+            return;
+        }
+    }
+    let _ = write.shutdown().await;
+}
+
+/// Resolves when the peer closes the connection or it fails
+async fn wait_closed(mut read: OwnedReadHalf) {
+    let mut buf = [0; 64];
+    while let Ok(1..) = read.read(&mut buf).await {}
+}
+
+/// Connection from a peer, its events are passed to `ChatServer`
+struct PeerSession {
+    server: Addr<ChatServer>,
+
+    /// Nodes seen on this connection
+    nodes: HashSet<u64>,
+}
+
+impl Actor for PeerSession {
+    type Context = Context<Self>;
+}
+
+impl StreamHandler<Result<String, io::Error>> for PeerSession {
+    fn handle(&mut self, line: Result<String, io::Error>, _: &mut Self::Context) {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                // stream ends after an error, `finished` cleans up
+                log::warn!("cluster peer error: {err}");
+                return;
+            }
+        };
+
+        match serde_json::from_str::<Event>(&line) {
+            Ok(event) => {
+                self.nodes.insert(event.node);
+                self.server.do_send(Remote(event));
+            }
+            Err(err) => log::warn!("invalid cluster event: {err}"),
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        for node in self.nodes.drain() {
+            self.server.do_send(NodeDown { node });
+        }
+        ctx.stop();
+    }
+}
@@ -0,0 +1,44 @@
+//! `@name` mentions. `ChatServer` tells the mentioned sessions about the
+//! message wherever they are and keeps the mentions of offline users until
+//! they are back.
+
+use bytestring::ByteString;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// How many unread mentions are kept per offline user, older ones are
+/// dropped
+pub const UNREAD_LIMIT: usize = 100;
+
+/// Characters that may follow a mention, like in `@alice, hi`
+const TRAILING: &[char] = &[',', '.', ':', ';', '!', '?', ')', '\'', '"'];
+
+/// Message that mentions a user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mention {
+    pub room: String,
+
+    /// Id of the message
+    pub id: u64,
+
+    /// Name of the author
+    pub by: String,
+
+    pub text: ByteString,
+
+    pub time: DateTime<Utc>,
+}
+
+/// Names mentioned in `text`, every name once
+pub fn parse(text: &str) -> Vec<&str> {
+    let mut names: Vec<&str> = text
+        .split_whitespace()
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|name| name.trim_end_matches(TRAILING))
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    names.sort_unstable();
+    names.dedup();
+    names
+}
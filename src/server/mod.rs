@@ -1,26 +1,220 @@
 //! `ChatServer` is an actor. It maintains list of connection client session.
-//! And manages available rooms. Peers send messages to other peers in same
-//! room through `ChatServer`.
+//! And manages available rooms. Each room is a separate `Room` actor, so
+//! `ChatServer` only routes messages to the room a session has joined.
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     sync::{
-        atomic::{AtomicUsize, Ordering},
-        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
 
+mod attachment;
+mod cluster;
 mod game;
+mod mention;
+mod moderation;
+mod outbox;
+mod presence;
+mod room;
+mod search;
+mod snapshot;
 
 use actix::prelude::*;
+use bytes::Bytes;
+use bytestring::ByteString;
+use chrono::{DateTime, Utc};
 use rand::{self, rngs::ThreadRng, Rng};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+pub use self::{
+    attachment::Attachment,
+    cluster::ClusterConfig,
+    mention::Mention,
+    moderation::{AuditAction, AuditEntry},
+    outbox::{Outbox, OutboxConfig, OutboxStats, OverflowPolicy, Receipt},
+    presence::{Status, UserPresence},
+    room::{HistoryPage, ReactionCount, StoredMessage},
+    search::{parse_time, SearchHit, SearchQuery},
+    snapshot::{SnapshotConfig, SnapshotInfo},
+};
+use self::{
+    attachment::ATTACHMENT_CHECK_INTERVAL,
+    cluster::{Cluster, EventKind},
+    mention::UNREAD_LIMIT,
+    moderation::AUDIT_LIMIT,
+    presence::{Presence, IDLE_CHECK_INTERVAL},
+    room::Room,
+    snapshot::{RoomSnapshot, Snapshot, SNAPSHOT_VERSION},
+};
+use crate::{attachments::AttachmentConfig, config::Config};
+
+/// Repeated typing starts of a session are forwarded at most this often
+const TYPING_THROTTLE: Duration = Duration::from_secs(2);
+
+/// Typing stops when the session does not repeat its start for this long
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+
+/// Content of a message sent to a session
+#[derive(Debug, Clone)]
+pub enum Payload {
+    /// Chat text and status messages
+    Text(ByteString),
+
+    /// Opaque data relayed between room members, sessions that can only
+    /// show text ignore it
+    Binary(Bytes),
+
+    /// Change in the room, sessions that can only show text ignore it
+    Event(RoomEvent),
+}
+
+/// Change in a room that is not a message
+#[derive(Debug, Clone)]
+pub enum RoomEvent {
+    /// Room member started or stopped typing
+    Typing { name: ByteString, active: bool },
+
+    /// Author changed the text of a message
+    Edited {
+        id: u64,
+        seq: u64,
+        text: ByteString,
+        time: DateTime<Utc>,
+    },
+
+    /// Message was removed by its author or a moderator
+    Deleted { id: u64, seq: u64 },
+
+    /// Message of the session was accepted, only sent to its author
+    Sent {
+        id: u64,
+        seq: u64,
+        time: DateTime<Utc>,
+    },
+
+    /// Reply in a thread the session follows, only sent to followers that
+    /// are not in the room
+    Thread {
+        room: String,
+        thread: u64,
+        id: u64,
+        text: ByteString,
+    },
+
+    /// Message that mentions the session, sent wherever the session is
+    Mention(Mention),
+
+    /// Session added or removed a reaction, `count` is the new number of
+    /// sessions that reacted with the emoji
+    Reaction {
+        id: u64,
+        seq: u64,
+        emoji: ByteString,
+        name: ByteString,
+        added: bool,
+        count: usize,
+    },
+}
+
+impl From<ByteString> for Payload {
+    fn from(text: ByteString) -> Self {
+        Payload::Text(text)
+    }
+}
+
+impl From<Bytes> for Payload {
+    fn from(data: Bytes) -> Self {
+        Payload::Binary(data)
+    }
+}
+
+/// Identity of a peer message, given when the chat server accepts it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageMeta {
+    /// Unique message id, JSON clients get it as string since it does not
+    /// fit a JavaScript number
+    #[serde(serialize_with = "serialize_id", deserialize_with = "deserialize_id")]
+    pub id: u64,
+
+    /// Server time the message was accepted
+    pub time: DateTime<Utc>,
+
+    /// Position in the room history, it only goes up within the room
+    pub seq: u64,
+
+    /// When the author last edited the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited: Option<DateTime<Utc>>,
+
+    /// Id of the first message of the thread, replies have one
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_thread",
+        deserialize_with = "deserialize_thread"
+    )]
+    pub thread: Option<u64>,
+
+    /// Name of the author as the chat server knows it, line protocols send
+    /// it as the origin of the message. JSON clients get it as `author` of
+    /// stored messages.
+    #[serde(skip)]
+    pub author: ByteString,
+}
+
+/// Ids are serialized as strings
+pub fn serialize_id<S: Serializer>(id: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(id)
+}
+
+pub fn deserialize_id<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(de::Error::custom)
+}
+
+fn serialize_thread<S: Serializer>(thread: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+    match thread {
+        Some(id) => serializer.collect_str(id),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_thread<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u64>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|id| id.parse().map_err(de::Error::custom))
+        .transpose()
+}
 
 /// Chat server sends this messages to session
+///
+/// Payload is a shared buffer, so broadcasting it to a room does not copy it
+/// for every recipient. Messages are delivered through the session `Outbox`.
 #[derive(Message)]
 #[rtype(result = "()")]
-pub struct Message(pub String);
+pub struct Message {
+    /// Message content
+    pub payload: Payload,
+
+    /// Id, time and position of peer messages, status messages have none
+    pub meta: Option<MessageMeta>,
+
+    /// The next message of the outbox is delivered when this is dropped.
+    /// Sessions keep it until the message is written to the peer.
+    pub receipt: Receipt,
+}
 
-/// Message for chat server communications
+impl Message {
+    /// Session fell behind and its outbox overflowed, it has to disconnect
+    pub fn overflowed(&self) -> bool {
+        self.receipt.overflowed()
+    }
+}
+
+// Message for chat server communications
 
 /// New chat session is created
 #[derive(Message)]
@@ -29,6 +223,21 @@ pub struct Connect {
     pub addr: Recipient<Message>,
 }
 
+/// Read-only subscriber for a room, it is not announced to the room and
+/// does not count as visitor. Use `Disconnect` to unsubscribe. Returns
+/// `None` when the room does not exist, subscribers never create rooms.
+#[derive(Message)]
+#[rtype(result = "Option<usize>")]
+pub struct Subscribe {
+    pub addr: Recipient<Message>,
+
+    /// Room name
+    pub room: String,
+
+    /// Replay stored messages after this sequence number
+    pub since: Option<u64>,
+}
+
 /// Session is disconnected
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -43,7 +252,64 @@ pub struct ClientMessage {
     /// Id of the client session
     pub id: usize,
     /// Peer message
-    pub msg: String,
+    pub msg: ByteString,
+    /// Room name
+    pub room: String,
+}
+
+/// Reply to message `parent` in the current room of the session, it
+/// starts a thread or continues the thread of `parent`
+pub struct ThreadReply {
+    /// Id of the client session
+    pub id: usize,
+
+    pub parent: u64,
+
+    /// Peer message
+    pub msg: ByteString,
+}
+
+impl actix::Message for ThreadReply {
+    type Result = Result<(), String>;
+}
+
+/// Follow or unfollow the thread of a message in the current room
+pub struct Follow {
+    /// Id of the client session
+    pub id: usize,
+
+    pub message_id: u64,
+
+    /// `false` stops following
+    pub follow: bool,
+}
+
+impl actix::Message for Follow {
+    type Result = Result<(), String>;
+}
+
+/// Stored messages of the thread of a message in the current room,
+/// first message first
+pub struct GetThread {
+    /// Id of the client session
+    pub id: usize,
+
+    pub message_id: u64,
+}
+
+impl actix::Message for GetThread {
+    type Result = Result<Vec<StoredMessage>, String>;
+}
+
+/// Relay opaque data to the other members of a room, it is not stored in
+/// the room history
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct ClientData {
+    /// Id of the client session
+    pub id: usize,
+    /// Peer data
+    pub data: Bytes,
     /// Room name
     pub room: String,
 }
@@ -55,6 +321,30 @@ impl actix::Message for ListRooms {
     type Result = Vec<String>;
 }
 
+/// Outbox state of every session
+pub struct Metrics;
+
+impl actix::Message for Metrics {
+    type Result = ServerMetrics;
+}
+
+#[derive(Debug)]
+pub struct ServerMetrics {
+    pub sessions: Vec<SessionMetrics>,
+
+    /// Sessions disconnected because their outbox overflowed, since start
+    pub overflows: u64,
+}
+
+/// Metrics of one session
+#[derive(Debug)]
+pub struct SessionMetrics {
+    pub id: usize,
+    pub room: Option<String>,
+    pub outbox: OutboxStats,
+    pub rtt: Option<Duration>,
+}
+
 /// Join room, if room does not exists create new one.
 #[derive(Message)]
 #[rtype(result = "()")]
@@ -66,43 +356,784 @@ pub struct Join {
     pub name: String,
 }
 
+/// Leave current room without joining another one
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    /// Client ID
+    pub id: usize,
+}
+
+/// Session set its name
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct SetName {
+    /// Client ID
+    pub id: usize,
+
+    /// Peer name
+    pub name: String,
+}
+
+/// Round-trip time of a session, measured by its heartbeat
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Rtt {
+    /// Client ID
+    pub id: usize,
+
+    pub rtt: Duration,
+}
+
+/// Local sessions in the room
+pub struct Members {
+    /// Room name
+    pub room: String,
+}
+
+impl actix::Message for Members {
+    type Result = Vec<Member>;
+}
+
+/// Session in a room
+#[derive(Debug)]
+pub struct Member {
+    /// Session name, sessions without name are `anonymous`
+    pub name: String,
+
+    /// Round-trip time, unknown until the first heartbeat
+    pub rtt: Option<Duration>,
+
+    pub status: Status,
+}
+
+/// Set session away, `/away [reason]`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Away {
+    /// Client ID
+    pub id: usize,
+
+    pub reason: Option<String>,
+}
+
+/// Session is back from away, `/back`
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Back {
+    /// Client ID
+    pub id: usize,
+}
+
+/// Session started or stopped typing, `/typing start|stop`.
+///
+/// It is not a chat message: it does not make the session active and is
+/// not stored in the room history.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Typing {
+    /// Client ID
+    pub id: usize,
+
+    pub active: bool,
+}
+
+/// Author changes a message in its current room
+pub struct Edit {
+    /// Client ID
+    pub id: usize,
+
+    pub message_id: u64,
+
+    pub text: String,
+}
+
+impl actix::Message for Edit {
+    type Result = Result<(), String>;
+}
+
+/// Author or a moderator deletes a message in its current room
+pub struct Delete {
+    /// Client ID
+    pub id: usize,
+
+    pub message_id: u64,
+}
+
+impl actix::Message for Delete {
+    type Result = Result<(), String>;
+}
+
+/// Session adds or removes a reaction on a message in its current room
+pub struct React {
+    /// Client ID
+    pub id: usize,
+
+    pub message_id: u64,
+
+    pub emoji: String,
+
+    /// `false` removes the reaction
+    pub add: bool,
+}
+
+impl actix::Message for React {
+    type Result = Result<(), String>;
+}
+
+/// Session becomes moderator of its current room with the moderator token
+pub struct Moderate {
+    /// Client ID
+    pub id: usize,
+
+    pub token: String,
+}
+
+impl actix::Message for Moderate {
+    type Result = Result<(), String>;
+}
+
+/// Token of the user of a session, it is created on first use. The token
+/// authenticates the HTTP routes and lets a new session `Resume` the user.
+pub struct GetToken {
+    /// Id of the client session
+    pub id: usize,
+}
+
+impl actix::Message for GetToken {
+    type Result = String;
+}
+
+/// Session continues as the user of an earlier session, it owns the
+/// messages and reactions of that user
+pub struct Resume {
+    /// Id of the client session
+    pub id: usize,
+
+    pub token: String,
+}
+
+impl actix::Message for Resume {
+    type Result = Result<(), String>;
+}
+
+/// Session of the user of a token, when that session is in `room`
+pub struct Authorize {
+    pub token: String,
+    pub room: String,
+}
+
+impl actix::Message for Authorize {
+    type Result = Result<usize, String>;
+}
+
+/// Uploaded file is stored, tell the room about it
+pub struct PostAttachment {
+    /// Id of the uploading session
+    pub id: usize,
+
+    pub room: String,
+
+    pub attachment: Attachment,
+}
+
+impl actix::Message for PostAttachment {
+    type Result = ();
+}
+
+/// Attachment with content hash `hash` shared in `room`
+pub struct GetAttachment {
+    pub room: String,
+    pub hash: String,
+}
+
+impl actix::Message for GetAttachment {
+    type Result = Option<Attachment>;
+}
+
+/// Write a snapshot of the rooms now
+pub struct TakeSnapshot;
+
+impl actix::Message for TakeSnapshot {
+    type Result = Result<SnapshotInfo, String>;
+}
+
+/// Audit trail of edited and deleted messages, oldest first
+pub struct GetAudit;
+
+impl actix::Message for GetAudit {
+    type Result = Vec<AuditEntry>;
+}
+
+/// Stored messages of a room after sequence number `since`, at most
+/// `limit` of them. `None` when the room does not exist.
+pub struct GetHistory {
+    pub room: String,
+    pub since: u64,
+    pub limit: usize,
+}
+
+impl actix::Message for GetHistory {
+    type Result = Option<HistoryPage>;
+}
+
+/// Search the stored messages of a room. `None` when the room does not
+/// exist.
+pub struct Search {
+    pub room: String,
+    pub query: SearchQuery,
+}
+
+impl actix::Message for Search {
+    type Result = Option<Vec<SearchHit>>;
+}
+
+/// Presence of the users, all connected sessions or the user with `name`
+pub struct GetPresence {
+    pub name: Option<String>,
+}
+
+impl actix::Message for GetPresence {
+    type Result = Vec<UserPresence>;
+}
+
 /// `ChatServer` manages chat rooms and responsible for coordinating chat session.
 ///
-/// Implementation is very naïve.
+/// It does not deliver room messages itself, it only keeps track of which
+/// room every session is in and forwards messages to the `Room` actors.
 #[derive(Debug)]
 pub struct ChatServer {
-    sessions: HashMap<usize, Recipient<Message>>,
-    rooms: HashMap<String, HashSet<usize>>, // Hashsetはsessionsのidと対応
+    sessions: HashMap<usize, Outbox>,
+    members: HashMap<usize, String>,  // session id -> joined room
+    watchers: HashMap<usize, String>, // subscriber id -> watched room
+    names: HashMap<usize, String>,
+    named: HashMap<String, HashSet<usize>>, // name -> sessions
+    users: HashMap<usize, usize>,           // session id -> resumed user
+    user_sessions: HashMap<usize, HashSet<usize>>, // user -> sessions
+    rtt: HashMap<usize, Duration>,
+    presence: HashMap<usize, Presence>,
+    typing: HashMap<usize, Instant>, // session id -> last forwarded start
+    last_seen: HashMap<String, DateTime<Utc>>, // name -> disconnect time
+    moderators: HashMap<String, HashSet<usize>>, // room -> moderator sessions
+    moderator_token: Option<String>,
+    audit: VecDeque<AuditEntry>,
+    threads: HashMap<u64, HashSet<usize>>, // first message id -> following users
+    unread: HashMap<usize, VecDeque<Mention>>, // user -> mentions while offline
+    offline: HashMap<String, usize>,       // name -> offline user with a token
+    tokens: HashMap<String, usize>,        // token -> user
+    attachments: HashMap<String, HashMap<String, Attachment>>, // room -> hash -> file
+    attachment_config: AttachmentConfig,
+    idle_timeout: Duration,
+    rooms: HashMap<String, Addr<Room>>,
+    remote: HashMap<String, HashSet<(u64, usize)>>, // room -> sessions on other nodes
+    arbiters: Vec<ArbiterHandle>,
+    next_arbiter: usize,
     rng: ThreadRng,
     visitor_count: Arc<AtomicUsize>,
+    outbox: OutboxConfig,
+    overflows: Arc<AtomicU64>,
+    cluster_config: Option<ClusterConfig>,
+    cluster: Option<Cluster>,
+    snapshot: Option<SnapshotConfig>,
+    snapshot_written: Arc<Mutex<DateTime<Utc>>>, // time of the last written snapshot
 }
 
-impl ChatServer {
-    pub fn new(visitor_count: Arc<AtomicUsize>) -> ChatServer {
-        // default room
-        let mut rooms = HashMap::new();
-        rooms.insert("main".to_owned(), HashSet::new());
+impl ChatServer {
+    pub fn new(visitor_count: Arc<AtomicUsize>, config: &Config) -> ChatServer {
+        // rooms are spread over one arbiter per cpu
+        let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let arbiters = (0..threads).map(|_| Arbiter::new().handle()).collect();
+
+        let mut server = ChatServer {
+            sessions: HashMap::new(),
+            members: HashMap::new(),
+            watchers: HashMap::new(),
+            names: HashMap::new(),
+            named: HashMap::new(),
+            users: HashMap::new(),
+            user_sessions: HashMap::new(),
+            rtt: HashMap::new(),
+            presence: HashMap::new(),
+            typing: HashMap::new(),
+            last_seen: HashMap::new(),
+            moderators: HashMap::new(),
+            moderator_token: config.moderator_token.clone(),
+            audit: VecDeque::new(),
+            threads: HashMap::new(),
+            unread: HashMap::new(),
+            offline: HashMap::new(),
+            tokens: HashMap::new(),
+            attachments: HashMap::new(),
+            attachment_config: config.attachments.clone(),
+            idle_timeout: config.idle_timeout,
+            rooms: HashMap::new(),
+            remote: HashMap::new(),
+            arbiters,
+            next_arbiter: 0,
+            rng: rand::thread_rng(),
+            visitor_count,
+            outbox: config.outbox.clone(),
+            overflows: Arc::default(),
+            cluster_config: config.cluster.clone(),
+            cluster: None,
+            snapshot: config.snapshot.clone(),
+            snapshot_written: Arc::new(Mutex::new(DateTime::<Utc>::MIN_UTC)),
+        };
+
+        let saved = config
+            .snapshot
+            .as_ref()
+            .and_then(|snapshot| snapshot::load(&snapshot.path));
+        if let Some(saved) = saved {
+            server.restore(saved);
+        }
+
+        // default room
+        server.room("main");
+        server
+    }
+}
+
+impl ChatServer {
+    /// Get room by name, if room does not exist start new one
+    fn room(&mut self, name: &str) -> Addr<Room> {
+        if let Some(addr) = self.rooms.get(name) {
+            return addr.clone();
+        }
+        self.start_room(name, None)
+    }
+
+    /// Start room on the next arbiter, with the state of a snapshot
+    fn start_room(&mut self, name: &str, saved: Option<RoomSnapshot>) -> Addr<Room> {
+        let arbiter = &self.arbiters[self.next_arbiter % self.arbiters.len()];
+        self.next_arbiter += 1;
+
+        let room_name = name.to_owned();
+        let addr = Room::start_in_arbiter(arbiter, move |_| match saved {
+            Some(saved) => Room::restore(saved),
+            None => Room::new(room_name),
+        });
+        self.rooms.insert(name.to_owned(), addr.clone());
+        addr
+    }
+
+    /// Start the rooms of a snapshot
+    fn restore(&mut self, saved: Snapshot) {
+        log::info!(
+            "restoring {} rooms with {} messages from the snapshot of {}",
+            saved.rooms.len(),
+            saved.messages(),
+            saved.time
+        );
+
+        self.audit = saved.audit.into();
+        self.last_seen = saved.last_seen;
+        self.tokens = saved.tokens;
+        self.offline = saved.offline;
+        self.unread = saved
+            .mentions
+            .into_iter()
+            .map(|(user, mentions)| (user, mentions.into()))
+            .collect();
+
+        for mut room in saved.rooms {
+            let files: HashMap<_, _> = room
+                .attachments
+                .drain(..)
+                .map(|attachment| (attachment.hash.clone(), attachment))
+                .collect();
+            if !files.is_empty() {
+                self.attachments.insert(room.name.clone(), files);
+            }
+
+            let name = room.name.clone();
+            self.start_room(&name, Some(room));
+        }
+    }
+
+    /// Send message to all users in the room
+    fn send_message(&self, room: &str, message: &str, skip_id: usize) {
+        if let Some(addr) = self.rooms.get(room) {
+            addr.do_send(room::Notice {
+                msg: ByteString::from(message),
+                skip_id,
+            });
+        }
+    }
+
+    /// User of the session, it owns messages and reactions across reconnects
+    fn user(&self, id: usize) -> usize {
+        self.users.get(&id).copied().unwrap_or(id)
+    }
+
+    /// Session no longer acts for its user. Users without sessions and
+    /// token can not come back, they stop following threads. Users with a
+    /// token get mentions of their last name until they resume.
+    fn release_user(&mut self, id: usize) {
+        let user = self.user(id);
+        if let Some(sessions) = self.user_sessions.get_mut(&user) {
+            sessions.remove(&id);
+            if !sessions.is_empty() {
+                return;
+            }
+            self.user_sessions.remove(&user);
+        }
+
+        if !self.tokens.values().any(|token_user| *token_user == user) {
+            self.threads.retain(|_, followers| {
+                followers.remove(&user);
+                !followers.is_empty()
+            });
+        } else if let Some(name) = self.names.get(&id) {
+            self.offline.insert(name.clone(), user);
+        }
+    }
+
+    /// Name the session, `None` removes its name
+    fn rename(&mut self, id: usize, name: Option<String>) {
+        if let Some(old) = self.names.remove(&id) {
+            if let Some(sessions) = self.named.get_mut(&old) {
+                sessions.remove(&id);
+                if sessions.is_empty() {
+                    self.named.remove(&old);
+                }
+            }
+        }
+
+        if let Some(name) = name {
+            self.named.entry(name.clone()).or_default().insert(id);
+            self.names.insert(id, name);
+        }
+    }
+
+    /// Session name, sessions without name are `anonymous`
+    fn display_name(&self, id: usize) -> String {
+        self.names
+            .get(&id)
+            .cloned()
+            .unwrap_or_else(|| "anonymous".to_owned())
+    }
+
+    /// Joined room of the session and its address
+    fn joined(&self, id: usize) -> Result<(String, Addr<Room>), String> {
+        self.members
+            .get(&id)
+            .and_then(|room| Some((room.clone(), self.rooms.get(room)?.clone())))
+            .ok_or_else(|| "you are not in a room".to_owned())
+    }
+
+    fn is_moderator(&self, id: usize, room: &str) -> bool {
+        self.moderators
+            .get(room)
+            .is_some_and(|sessions| sessions.contains(&id))
+    }
+
+    /// Accept peer message, give it an id and send it to the room. Replies
+    /// name the first message of their `thread`.
+    fn post(&mut self, id: usize, room: &str, msg: ByteString, thread: Option<u64>) {
+        self.stop_typing(id);
+
+        if let Some(presence) = self.presence.get_mut(&id) {
+            if presence.active() {
+                self.announce(id);
+            }
+        }
+
+        let message_id = self.rng.gen::<u64>();
+        let time = Utc::now();
+        let name = self.display_name(id);
+
+        // the text is only copied for other nodes
+        if self.cluster.is_some() {
+            self.publish(
+                room,
+                EventKind::Message {
+                    id: message_id,
+                    time,
+                    name: name.clone(),
+                    text: msg.to_string(),
+                    thread,
+                },
+            );
+        }
+
+        if let Some(thread) = thread {
+            self.notify_thread(id, room, thread, message_id, &msg);
+        }
+        self.notify_mentions(id, room, message_id, time, &msg);
+
+        if let Some(addr) = self.rooms.get(room) {
+            addr.do_send(room::Publish {
+                id,
+                author: self.user(id),
+                name,
+                message_id,
+                time,
+                msg,
+                thread,
+            });
+        }
+    }
+
+    /// Notify the thread of a reply from another node, the author of a local
+    /// first message takes part in its thread
+    fn notify_remote_reply(
+        &mut self,
+        room: &str,
+        thread: u64,
+        message_id: u64,
+        msg: ByteString,
+        ctx: &mut Context<Self>,
+    ) {
+        let Some(addr) = self.rooms.get(room) else {
+            self.notify_thread(0, room, thread, message_id, &msg);
+            return;
+        };
+
+        let room = room.to_owned();
+        let root = room::ThreadRoot { message_id: thread };
+        ctx.spawn(addr.send(root).into_actor(self).map(move |res, act, _| {
+            if let Ok(Ok((_, author))) = res {
+                if author != 0 {
+                    act.threads.entry(thread).or_default().insert(author);
+                }
+            }
+            act.notify_thread(0, &room, thread, message_id, &msg);
+        }));
+    }
+
+    /// Tell the users that take part in or follow the thread about the
+    /// reply, wherever they are. The sender takes part from now on, replies
+    /// of other nodes have no sender `id`.
+    fn notify_thread(
+        &mut self,
+        id: usize,
+        room: &str,
+        thread: u64,
+        message_id: u64,
+        msg: &ByteString,
+    ) {
+        let sender = (id != 0).then(|| self.user(id));
+        if sender.is_none() && !self.threads.contains_key(&thread) {
+            return;
+        }
+        let followers = self.threads.entry(thread).or_default();
+        followers.extend(sender);
+
+        let event = Payload::Event(RoomEvent::Thread {
+            room: room.to_owned(),
+            thread,
+            id: message_id,
+            text: msg.clone(),
+        });
+        for user in followers.iter().filter(|user| Some(**user) != sender) {
+            let sessions = self.user_sessions.get(user).into_iter().flatten();
+            for outbox in sessions.filter_map(|session| self.sessions.get(session)) {
+                outbox.push(event.clone(), None);
+            }
+        }
+    }
+
+    /// Tell the sessions named with `@name` in the message about it, the
+    /// mentions of offline users with a token wait until they resume
+    fn notify_mentions(
+        &mut self,
+        id: usize,
+        room: &str,
+        message_id: u64,
+        time: DateTime<Utc>,
+        msg: &ByteString,
+    ) {
+        let names = mention::parse(msg);
+        if names.is_empty() {
+            return;
+        }
+
+        let by = self.display_name(id);
+        for name in names {
+            let mention = Mention {
+                room: room.to_owned(),
+                id: message_id,
+                by: by.clone(),
+                text: msg.clone(),
+                time,
+            };
+
+            let sessions = self.named.get(name).into_iter().flatten();
+            let mut sessions = sessions.filter(|session| **session != id).peekable();
+            if sessions.peek().is_some() {
+                let event = Payload::Event(RoomEvent::Mention(mention));
+                for outbox in sessions.filter_map(|session| self.sessions.get(session)) {
+                    outbox.push(event.clone(), None);
+                }
+            } else if let Some(user) = self.offline.get(name) {
+                let unread = self.unread.entry(*user).or_default();
+                unread.push_back(mention);
+                if unread.len() > UNREAD_LIMIT {
+                    unread.pop_front();
+                }
+            }
+        }
+    }
+
+    /// Keep removed text in the audit trail
+    fn audit(&mut self, entry: AuditEntry) {
+        log::info!(
+            "{} {:?} of message {} in {:?}",
+            entry.by,
+            entry.action,
+            entry.message_id,
+            entry.room
+        );
+
+        if self.audit.len() == AUDIT_LIMIT {
+            self.audit.pop_front();
+        }
+        self.audit.push_back(entry);
+    }
+
+    /// Tell the room of a named session that its presence changed
+    fn announce(&self, id: usize) {
+        let (Some(name), Some(room), Some(presence)) = (
+            self.names.get(&id),
+            self.members.get(&id),
+            self.presence.get(&id),
+        ) else {
+            return;
+        };
+
+        self.send_message(room, &presence.describe(name), id);
+    }
+
+    /// Mark sessions idle that did not send a message for `idle_timeout`
+    fn check_idle(&mut self) {
+        let idle: Vec<usize> = self
+            .presence
+            .iter_mut()
+            .filter_map(|(id, presence)| presence.check_idle(self.idle_timeout).then_some(*id))
+            .collect();
+
+        for id in idle {
+            self.announce(id);
+        }
+    }
+
+    /// Tell the room of the session that it started or stopped typing
+    fn send_typing(&self, id: usize, active: bool) {
+        let Some(addr) = self.members.get(&id).and_then(|room| self.rooms.get(room)) else {
+            return;
+        };
+
+        let name = self.names.get(&id).map_or("anonymous", String::as_str);
+        addr.do_send(room::Typing {
+            id,
+            name: ByteString::from(name),
+            active,
+        });
+    }
+
+    /// Session stopped typing, by itself or because it sent its message
+    fn stop_typing(&mut self, id: usize) {
+        if self.typing.remove(&id).is_some() {
+            self.send_typing(id, false);
+        }
+    }
+
+    /// Stop typing of sessions that did not repeat their start
+    fn expire_typing(&mut self) {
+        let expired: Vec<usize> = self
+            .typing
+            .iter()
+            .filter(|(_, started)| started.elapsed() > TYPING_TIMEOUT)
+            .map(|(id, _)| *id)
+            .collect();
+
+        for id in expired {
+            self.stop_typing(id);
+        }
+    }
+
+    /// Forget attachments shared longer than `ATTACHMENT_TTL` ago and remove
+    /// the files no room shares anymore
+    fn expire_attachments(&mut self) {
+        let ttl = self.attachment_config.ttl;
+        if ttl.is_zero() {
+            return;
+        }
+        let Ok(age) = chrono::Duration::from_std(ttl) else {
+            return;
+        };
+
+        let expires = Utc::now() - age;
+        let mut expired = HashSet::new();
+        self.attachments.retain(|_, files| {
+            files.retain(|hash, attachment| {
+                let keep = attachment.time > expires;
+                if !keep {
+                    expired.insert(hash.clone());
+                }
+                keep
+            });
+            !files.is_empty()
+        });
 
-        ChatServer {
-            sessions: HashMap::new(),
-            rooms,
-            rng: rand::thread_rng(),
-            visitor_count,
+        let unshared: Vec<String> = expired
+            .into_iter()
+            .filter(|hash| {
+                !self
+                    .attachments
+                    .values()
+                    .any(|files| files.contains_key(hash))
+            })
+            .collect();
+        if unshared.is_empty() {
+            return;
         }
+
+        let dir = self.attachment_config.dir.clone();
+        let before = std::time::SystemTime::now() - ttl;
+        actix_web::rt::task::spawn_blocking(move || {
+            attachment::remove_files(dir, unshared, before)
+        });
     }
-}
 
-impl ChatServer {
-    /// Send message to all users in the room
-    fn send_message(&self, room: &str, message: &str, skip_id: usize) {
-        if let Some(sessions) = self.rooms.get(room) {
-            for id in sessions {
-                if *id != skip_id {
-                    if let Some(addr) = self.sessions.get(id) {
-                        addr.do_send(Message(message.to_owned()));
-                    }
-                }
+    /// Presence of a connected session
+    fn user_presence(&self, id: usize, presence: &Presence) -> UserPresence {
+        UserPresence {
+            name: self.display_name(id),
+            status: presence.status,
+            room: self.members.get(&id).cloned(),
+            reason: presence.reason.clone(),
+            idle_seconds: Some(presence.last_active.elapsed().as_secs()),
+            last_seen: None,
+        }
+    }
+
+    /// Send event to other nodes
+    fn publish(&self, room: &str, kind: EventKind) {
+        if let Some(cluster) = &self.cluster {
+            cluster.publish(room, kind);
+        }
+    }
+
+    /// Remove session from its current room
+    fn leave(&mut self, id: usize) {
+        self.stop_typing(id);
+
+        if let Some(name) = self.members.remove(&id) {
+            if let Some(addr) = self.rooms.get(&name) {
+                addr.do_send(room::Leave { id });
             }
+            self.publish(&name, EventKind::Leave { session: id });
+        }
+    }
+
+    /// Add session to the room, room is created if needed
+    fn enter(&mut self, id: usize, name: &str) {
+        if let Some(outbox) = self.sessions.get(&id).cloned() {
+            self.room(name).do_send(room::Enter { id, outbox });
+            self.members.insert(id, name.to_owned());
+            self.publish(name, EventKind::Enter { session: id });
         }
     }
 }
@@ -112,6 +1143,22 @@ impl Actor for ChatServer {
     /// We are going to use simple Context, we just need ability to communicate
     /// with other actors.
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if let Some(config) = &self.cluster_config {
+            self.cluster = Some(Cluster::start(config, ctx.address()));
+        }
+
+        ctx.run_interval(IDLE_CHECK_INTERVAL, |act, _| act.check_idle());
+        ctx.run_interval(TYPING_THROTTLE, |act, _| act.expire_typing());
+        ctx.run_interval(ATTACHMENT_CHECK_INTERVAL, |act, _| act.expire_attachments());
+
+        if let Some(interval) = self.snapshot.as_ref().map(|snapshot| snapshot.interval) {
+            if !interval.is_zero() {
+                ctx.run_interval(interval, |_, ctx| ctx.notify(TakeSnapshot));
+            }
+        }
+    }
 }
 
 /// Handler for Connect message.
@@ -128,10 +1175,15 @@ impl Handler<Connect> for ChatServer {
 
         // register session with random id
         let id = self.rng.gen::<usize>();
-        self.sessions.insert(id, msg.addr);
+        self.sessions.insert(
+            id,
+            Outbox::new(msg.addr, self.outbox.clone(), self.overflows.clone()),
+        );
+        self.presence.insert(id, Presence::new());
+        self.user_sessions.entry(id).or_default().insert(id);
 
         // auto join session to main room
-        self.rooms.entry("main".to_owned()).or_default().insert(id);
+        self.enter(id, "main");
 
         let count = self.visitor_count.fetch_add(1, Ordering::SeqCst);
         self.send_message("main", &format!("Total visitors {count}"), 0);
@@ -141,37 +1193,632 @@ impl Handler<Connect> for ChatServer {
     }
 }
 
+/// Handler for Subscribe message.
+impl Handler<Subscribe> for ChatServer {
+    type Result = Option<usize>;
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) -> Self::Result {
+        let remote = self.remote.get(&msg.room).is_some_and(|s| !s.is_empty());
+        if !self.rooms.contains_key(&msg.room) && !remote {
+            return None;
+        }
+
+        // watchers only get the broadcasts of the room, they are no members
+        let id = self.rng.gen::<usize>();
+        let outbox = Outbox::new(msg.addr, self.outbox.clone(), self.overflows.clone());
+        self.room(&msg.room).do_send(room::Watch {
+            id,
+            outbox,
+            since: msg.since,
+        });
+        self.watchers.insert(id, msg.room);
+
+        Some(id)
+    }
+}
+
 /// Handler for Disconnect message.
 impl Handler<Disconnect> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: Disconnect, _: &mut Context<Self>) {
+        if let Some(room) = self.watchers.remove(&msg.id) {
+            if let Some(addr) = self.rooms.get(&room) {
+                addr.do_send(room::Leave { id: msg.id });
+            }
+            return;
+        }
+
         println!("Someone disconnected");
 
-        let mut rooms: Vec<String> = Vec::new();
+        // named users stay known as offline
+        if let Some(mut presence) = self.presence.remove(&msg.id) {
+            if let (Some(name), Some(room)) = (self.names.get(&msg.id), self.members.get(&msg.id)) {
+                presence.status = Status::Offline;
+                presence.reason = None;
+                self.send_message(room, &presence.describe(name), msg.id);
+                self.last_seen.insert(name.clone(), Utc::now());
+            }
+        }
+
+        for sessions in self.moderators.values_mut() {
+            sessions.remove(&msg.id);
+        }
+        self.release_user(msg.id);
+        self.users.remove(&msg.id);
 
-        // remove address
+        // remove address, room sends message to other users
         if self.sessions.remove(&msg.id).is_some() {
-            // remove session from all rooms
-            for (name, sessions) in &mut self.rooms {
-                if sessions.remove(&msg.id) {
-                    rooms.push(name.to_owned());
+            self.leave(msg.id);
+        }
+        self.rename(msg.id, None);
+        self.rtt.remove(&msg.id);
+    }
+}
+
+/// Handler for Leave message.
+impl Handler<Leave> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) {
+        self.leave(msg.id);
+    }
+}
+
+/// Handler for `SetName` message.
+impl Handler<SetName> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetName, _: &mut Context<Self>) {
+        self.last_seen.remove(&msg.name);
+        self.rename(msg.id, Some(msg.name));
+    }
+}
+
+/// Handler for `Away` message.
+impl Handler<Away> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Away, _: &mut Context<Self>) {
+        if let Some(presence) = self.presence.get_mut(&msg.id) {
+            presence.away(msg.reason);
+            self.announce(msg.id);
+        }
+    }
+}
+
+/// Handler for `Back` message.
+impl Handler<Back> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Back, _: &mut Context<Self>) {
+        if let Some(presence) = self.presence.get_mut(&msg.id) {
+            if presence.back() {
+                self.announce(msg.id);
+            }
+        }
+    }
+}
+
+/// Handler for `Typing` message.
+impl Handler<Typing> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Typing, _: &mut Context<Self>) {
+        if !msg.active {
+            self.stop_typing(msg.id);
+            return;
+        }
+
+        if self
+            .typing
+            .get(&msg.id)
+            .is_some_and(|last| last.elapsed() < TYPING_THROTTLE)
+        {
+            return;
+        }
+
+        if self.members.contains_key(&msg.id) {
+            self.typing.insert(msg.id, Instant::now());
+            self.send_typing(msg.id, true);
+        }
+    }
+}
+
+/// Handler for `GetHistory` message.
+///
+/// The room answers, so a long replay does not hold up the chat server.
+impl Handler<GetHistory> for ChatServer {
+    type Result = ResponseFuture<Option<HistoryPage>>;
+
+    fn handle(&mut self, msg: GetHistory, _: &mut Context<Self>) -> Self::Result {
+        let addr = self.rooms.get(&msg.room).cloned();
+
+        Box::pin(async move {
+            addr?
+                .send(room::History {
+                    since: msg.since,
+                    limit: msg.limit,
+                })
+                .await
+                .ok()
+        })
+    }
+}
+
+/// Handler for `Search` message.
+impl Handler<Search> for ChatServer {
+    type Result = ResponseFuture<Option<Vec<SearchHit>>>;
+
+    fn handle(&mut self, msg: Search, _: &mut Context<Self>) -> Self::Result {
+        let addr = self.rooms.get(&msg.room).cloned();
+
+        Box::pin(async move { addr?.send(room::Search { query: msg.query }).await.ok() })
+    }
+}
+
+/// Handler for `Edit` message.
+impl Handler<Edit> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<(), String>>;
+
+    fn handle(&mut self, msg: Edit, _: &mut Context<Self>) -> Self::Result {
+        let (room, addr) = match self.joined(msg.id) {
+            Ok(joined) => joined,
+            Err(err) => return Box::pin(fut::ready(Err(err))),
+        };
+        let by = self.display_name(msg.id);
+
+        let edit = room::Edit {
+            author: self.user(msg.id),
+            message_id: msg.message_id,
+            text: msg.text.into(),
+        };
+        Box::pin(addr.send(edit).into_actor(self).map(move |res, act, _| {
+            let old = res.map_err(|_| "room is gone".to_owned())??;
+            act.audit(AuditEntry::new(AuditAction::Edit, room, &old, by, false));
+            Ok(())
+        }))
+    }
+}
+
+/// Handler for `Delete` message.
+impl Handler<Delete> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<(), String>>;
+
+    fn handle(&mut self, msg: Delete, _: &mut Context<Self>) -> Self::Result {
+        let (room, addr) = match self.joined(msg.id) {
+            Ok(joined) => joined,
+            Err(err) => return Box::pin(fut::ready(Err(err))),
+        };
+        let by = self.display_name(msg.id);
+
+        let author = self.user(msg.id);
+        let delete = room::Delete {
+            author,
+            message_id: msg.message_id,
+            moderator: self.is_moderator(msg.id, &room),
+        };
+        Box::pin(addr.send(delete).into_actor(self).map(move |res, act, _| {
+            let old = res.map_err(|_| "room is gone".to_owned())??;
+            let moderator = old.author != author;
+            act.audit(AuditEntry::new(
+                AuditAction::Delete,
+                room,
+                &old,
+                by,
+                moderator,
+            ));
+            Ok(())
+        }))
+    }
+}
+
+/// Handler for `React` message.
+impl Handler<React> for ChatServer {
+    type Result = ResponseFuture<Result<(), String>>;
+
+    fn handle(&mut self, msg: React, _: &mut Context<Self>) -> Self::Result {
+        let (_, addr) = match self.joined(msg.id) {
+            Ok(joined) => joined,
+            Err(err) => return Box::pin(async move { Err(err) }),
+        };
+
+        let react = room::React {
+            author: self.user(msg.id),
+            name: self.display_name(msg.id).into(),
+            message_id: msg.message_id,
+            emoji: msg.emoji.into(),
+            add: msg.add,
+        };
+        Box::pin(async move {
+            addr.send(react)
+                .await
+                .map_err(|_| "room is gone".to_owned())?
+        })
+    }
+}
+
+/// Handler for `Moderate` message.
+impl Handler<Moderate> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Moderate, _: &mut Context<Self>) -> Self::Result {
+        let Some(token) = &self.moderator_token else {
+            return Err("moderation is disabled".to_owned());
+        };
+        if *token != msg.token {
+            return Err("wrong moderator token".to_owned());
+        }
+
+        let (room, _) = self.joined(msg.id)?;
+        self.moderators.entry(room).or_default().insert(msg.id);
+        Ok(())
+    }
+}
+
+/// Handler for `GetToken` message.
+impl Handler<GetToken> for ChatServer {
+    type Result = String;
+
+    fn handle(&mut self, msg: GetToken, _: &mut Context<Self>) -> Self::Result {
+        let user = self.user(msg.id);
+        if let Some((token, _)) = self.tokens.iter().find(|(_, id)| **id == user) {
+            return token.clone();
+        }
+
+        let token = format!(
+            "{:016x}{:016x}",
+            self.rng.gen::<u64>(),
+            self.rng.gen::<u64>()
+        );
+        self.tokens.insert(token.clone(), user);
+        token
+    }
+}
+
+/// Handler for `Resume` message.
+impl Handler<Resume> for ChatServer {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Resume, _: &mut Context<Self>) -> Self::Result {
+        let user = *self
+            .tokens
+            .get(&msg.token)
+            .ok_or_else(|| "invalid token".to_owned())?;
+
+        self.release_user(msg.id);
+        self.users.insert(msg.id, user);
+        self.user_sessions.entry(user).or_default().insert(msg.id);
+
+        // mentions while the user was offline
+        self.offline.retain(|_, offline| *offline != user);
+        if let Some(unread) = self.unread.remove(&user) {
+            if let Some(outbox) = self.sessions.get(&msg.id) {
+                for mention in unread {
+                    outbox.push(Payload::Event(RoomEvent::Mention(mention)), None);
                 }
             }
         }
-        // send message to other users
-        for room in rooms {
-            self.send_message(&room, "Someone disconnected", 0);
+        Ok(())
+    }
+}
+
+/// Handler for `Authorize` message.
+impl Handler<Authorize> for ChatServer {
+    type Result = Result<usize, String>;
+
+    fn handle(&mut self, msg: Authorize, _: &mut Context<Self>) -> Self::Result {
+        let user = *self
+            .tokens
+            .get(&msg.token)
+            .ok_or_else(|| "invalid token".to_owned())?;
+
+        self.members
+            .iter()
+            .find(|(id, room)| self.user(**id) == user && **room == msg.room)
+            .map(|(id, _)| *id)
+            .ok_or_else(|| "you are not in this room".to_owned())
+    }
+}
+
+/// Handler for `PostAttachment` message.
+impl Handler<PostAttachment> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: PostAttachment, _: &mut Context<Self>) {
+        let attachment = msg.attachment;
+        let text = format!(
+            "{} shared {} ({} bytes) {}",
+            self.display_name(msg.id),
+            attachment.label(),
+            attachment.size,
+            attachment.url(&msg.room),
+        );
+
+        self.attachments
+            .entry(msg.room.clone())
+            .or_default()
+            .insert(attachment.hash.clone(), attachment);
+        self.post(msg.id, &msg.room, text.into(), None);
+    }
+}
+
+/// Handler for `GetAttachment` message.
+impl Handler<GetAttachment> for ChatServer {
+    type Result = Option<Attachment>;
+
+    fn handle(&mut self, msg: GetAttachment, _: &mut Context<Self>) -> Self::Result {
+        self.attachments.get(&msg.room)?.get(&msg.hash).cloned()
+    }
+}
+
+/// Handler for `TakeSnapshot` message.
+///
+/// Rooms are asked for their state one after another, the file is written
+/// off the actor thread. Snapshots that overlap are written one at a time,
+/// one that was taken before the last written one is dropped.
+impl Handler<TakeSnapshot> for ChatServer {
+    type Result = ResponseFuture<Result<SnapshotInfo, String>>;
+
+    fn handle(&mut self, _: TakeSnapshot, _: &mut Context<Self>) -> Self::Result {
+        let Some(config) = self.snapshot.clone() else {
+            return Box::pin(async { Err("snapshots are disabled".to_owned()) });
+        };
+
+        let rooms: Vec<_> = self
+            .rooms
+            .iter()
+            .map(|(name, addr)| (name.clone(), addr.clone()))
+            .collect();
+        let written = self.snapshot_written.clone();
+        let mut attachments = self.attachments.clone();
+        let mut saved = Snapshot {
+            version: SNAPSHOT_VERSION,
+            time: Utc::now(),
+            rooms: Vec::with_capacity(rooms.len()),
+            audit: self.audit.iter().cloned().collect(),
+            last_seen: self.last_seen.clone(),
+            tokens: self.tokens.clone(),
+            mentions: self
+                .unread
+                .iter()
+                .map(|(user, mentions)| (*user, mentions.iter().cloned().collect()))
+                .collect(),
+            offline: self.offline.clone(),
+        };
+
+        Box::pin(async move {
+            // a partial snapshot must not replace the last complete one
+            for (name, addr) in rooms {
+                let Ok(mut room) = addr.send(room::Save).await else {
+                    return Err(format!("room {name} did not answer"));
+                };
+                room.attachments = attachments
+                    .remove(&room.name)
+                    .map(|files| files.into_values().collect())
+                    .unwrap_or_default();
+                saved.rooms.push(room);
+            }
+
+            let info = SnapshotInfo {
+                path: config.path.display().to_string(),
+                time: saved.time,
+                rooms: saved.rooms.len(),
+                messages: saved.messages(),
+            };
+            let res = actix_web::rt::task::spawn_blocking(move || {
+                snapshot::save(&config.path, &saved, &written)
+            })
+            .await;
+
+            match res {
+                Ok(Ok(())) => {
+                    log::debug!("snapshot of {} rooms written to {}", info.rooms, info.path);
+                    Ok(info)
+                }
+                Ok(Err(err)) => {
+                    log::error!("can not write snapshot {}: {err}", info.path);
+                    Err(err.to_string())
+                }
+                Err(err) => Err(err.to_string()),
+            }
+        })
+    }
+}
+
+/// Handler for `GetAudit` message.
+impl Handler<GetAudit> for ChatServer {
+    type Result = MessageResult<GetAudit>;
+
+    fn handle(&mut self, _: GetAudit, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(self.audit.iter().cloned().collect())
+    }
+}
+
+/// Handler for `GetPresence` message.
+impl Handler<GetPresence> for ChatServer {
+    type Result = MessageResult<GetPresence>;
+
+    fn handle(&mut self, msg: GetPresence, _: &mut Context<Self>) -> Self::Result {
+        let mut users: Vec<UserPresence> = self
+            .presence
+            .iter()
+            .map(|(id, presence)| self.user_presence(*id, presence))
+            .filter(|user| msg.name.as_ref().is_none_or(|name| *name == user.name))
+            .collect();
+
+        if let Some(name) = msg.name {
+            if let (true, Some(last_seen)) = (users.is_empty(), self.last_seen.get(&name)) {
+                users.push(UserPresence {
+                    name,
+                    status: Status::Offline,
+                    room: None,
+                    reason: None,
+                    idle_seconds: None,
+                    last_seen: Some(*last_seen),
+                });
+            }
+        }
+
+        users.sort_by(|a, b| a.name.cmp(&b.name));
+        MessageResult(users)
+    }
+}
+
+/// Handler for `Rtt` message.
+impl Handler<Rtt> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: Rtt, _: &mut Context<Self>) {
+        if self.sessions.contains_key(&msg.id) {
+            self.rtt.insert(msg.id, msg.rtt);
         }
     }
 }
 
+/// Handler for `Members` message.
+impl Handler<Members> for ChatServer {
+    type Result = MessageResult<Members>;
+
+    fn handle(&mut self, msg: Members, _: &mut Context<Self>) -> Self::Result {
+        let members = self
+            .members
+            .iter()
+            .filter(|(_, room)| **room == msg.room)
+            .map(|(id, _)| Member {
+                name: self.display_name(*id),
+                rtt: self.rtt.get(id).copied(),
+                status: self
+                    .presence
+                    .get(id)
+                    .map_or(Status::Online, |presence| presence.status),
+            })
+            .collect();
+
+        MessageResult(members)
+    }
+}
+
 /// Handler for Message message.
+///
+/// Message is routed to the room actor, which delivers it to its members.
 impl Handler<ClientMessage> for ChatServer {
     type Result = ();
 
     fn handle(&mut self, msg: ClientMessage, _: &mut Context<Self>) {
-        self.send_message(&msg.room, msg.msg.as_str(), msg.id);
+        self.post(msg.id, &msg.room, msg.msg, None);
+    }
+}
+
+/// Handler for `ThreadReply` message.
+///
+/// The room knows whether the parent exists and which thread it is in.
+impl Handler<ThreadReply> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<(), String>>;
+
+    fn handle(&mut self, msg: ThreadReply, _: &mut Context<Self>) -> Self::Result {
+        let (room, addr) = match self.joined(msg.id) {
+            Ok(joined) => joined,
+            Err(err) => return Box::pin(fut::ready(Err(err))),
+        };
+
+        let root = room::ThreadRoot {
+            message_id: msg.parent,
+        };
+        Box::pin(addr.send(root).into_actor(self).map(move |res, act, _| {
+            let (thread, author) = res.map_err(|_| "room is gone".to_owned())??;
+
+            // the author of the first message takes part in its thread
+            if author != 0 {
+                act.threads.entry(thread).or_default().insert(author);
+            }
+            act.post(msg.id, &room, msg.msg, Some(thread));
+            Ok(())
+        }))
+    }
+}
+
+/// Handler for `Follow` message.
+impl Handler<Follow> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<(), String>>;
+
+    fn handle(&mut self, msg: Follow, _: &mut Context<Self>) -> Self::Result {
+        let user = self.user(msg.id);
+
+        // notifications come from other rooms, unfollowing them by thread id
+        // works from anywhere
+        if !msg.follow {
+            if let Some(followers) = self.threads.get_mut(&msg.message_id) {
+                followers.remove(&user);
+                if followers.is_empty() {
+                    self.threads.remove(&msg.message_id);
+                }
+                return Box::pin(fut::ready(Ok(())));
+            }
+        }
+
+        let addr = match self.joined(msg.id) {
+            Ok((_, addr)) => addr,
+            Err(err) => return Box::pin(fut::ready(Err(err))),
+        };
+
+        let root = room::ThreadRoot {
+            message_id: msg.message_id,
+        };
+        Box::pin(addr.send(root).into_actor(self).map(move |res, act, _| {
+            let (thread, _) = res.map_err(|_| "room is gone".to_owned())??;
+
+            if msg.follow {
+                act.threads.entry(thread).or_default().insert(user);
+            } else if let Some(followers) = act.threads.get_mut(&thread) {
+                followers.remove(&user);
+                if followers.is_empty() {
+                    act.threads.remove(&thread);
+                }
+            }
+            Ok(())
+        }))
+    }
+}
+
+/// Handler for `GetThread` message.
+impl Handler<GetThread> for ChatServer {
+    type Result = ResponseFuture<Result<Vec<StoredMessage>, String>>;
+
+    fn handle(&mut self, msg: GetThread, _: &mut Context<Self>) -> Self::Result {
+        let addr = match self.joined(msg.id) {
+            Ok((_, addr)) => addr,
+            Err(err) => return Box::pin(async move { Err(err) }),
+        };
+
+        Box::pin(async move {
+            addr.send(room::Thread {
+                message_id: msg.message_id,
+            })
+            .await
+            .map_err(|_| "room is gone".to_owned())?
+        })
+    }
+}
+
+/// Handler for `ClientData` message.
+impl Handler<ClientData> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: ClientData, _: &mut Context<Self>) {
+        self.publish(
+            &msg.room,
+            EventKind::Data {
+                data: msg.data.to_vec(),
+            },
+        );
+
+        if let Some(addr) = self.rooms.get(&msg.room) {
+            addr.do_send(room::Relay {
+                id: msg.id,
+                data: msg.data,
+            });
+        }
     }
 }
 
@@ -186,6 +1833,13 @@ impl Handler<ListRooms> for ChatServer {
             rooms.push(key.to_owned())
         }
 
+        // rooms that only exist on other nodes
+        for (key, sessions) in &self.remote {
+            if !sessions.is_empty() && !self.rooms.contains_key(key) {
+                rooms.push(key.to_owned())
+            }
+        }
+
         MessageResult(rooms)
     }
 }
@@ -197,21 +1851,136 @@ impl Handler<Join> for ChatServer {
 
     fn handle(&mut self, msg: Join, _: &mut Context<Self>) {
         let Join { id, name } = msg;
-        let mut rooms = Vec::new();
 
-        // remove session from all rooms
-        for (n, sessions) in &mut self.rooms {
-            if sessions.remove(&id) {
-                rooms.push(n.to_owned());
+        // remove session from old room, room sends message to other users
+        self.leave(id);
+
+        self.enter(id, &name);
+
+        self.send_message(&name, "Someone connected", id);
+    }
+}
+
+/// Handler for `Metrics` message.
+impl Handler<Metrics> for ChatServer {
+    type Result = MessageResult<Metrics>;
+
+    fn handle(&mut self, _: Metrics, _: &mut Context<Self>) -> Self::Result {
+        let sessions = self
+            .sessions
+            .iter()
+            .map(|(id, outbox)| SessionMetrics {
+                id: *id,
+                room: self.members.get(id).cloned(),
+                outbox: outbox.stats(),
+                rtt: self.rtt.get(id).copied(),
+            })
+            .collect();
+
+        MessageResult(ServerMetrics {
+            sessions,
+            overflows: self.overflows.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Handler for events from other nodes.
+///
+/// Remote sessions are not members of the local room actors, their messages
+/// and presence are delivered to the local members of the room.
+impl Handler<cluster::Remote> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: cluster::Remote, ctx: &mut Context<Self>) {
+        let cluster::Event { node, room, kind } = msg.0;
+
+        if self.cluster.as_ref().map(Cluster::node) == Some(node) {
+            return;
+        }
+
+        match kind {
+            EventKind::Message {
+                id,
+                time,
+                name,
+                text,
+                thread,
+            } => {
+                let text = ByteString::from(text);
+                if let Some(thread) = thread {
+                    self.notify_remote_reply(&room, thread, id, text.clone(), ctx);
+                }
+                if let Some(addr) = self.rooms.get(&room) {
+                    addr.do_send(room::Publish {
+                        id: 0,
+                        author: 0,
+                        name,
+                        message_id: id,
+                        time,
+                        msg: text,
+                        thread,
+                    });
+                }
+            }
+            EventKind::Data { data } => {
+                if let Some(addr) = self.rooms.get(&room) {
+                    addr.do_send(room::Relay {
+                        id: 0,
+                        data: data.into(),
+                    });
+                }
+            }
+            EventKind::Enter { session } => {
+                self.remote
+                    .entry(room.clone())
+                    .or_default()
+                    .insert((node, session));
+                self.send_message(&room, "Someone connected", 0);
+            }
+            EventKind::Leave { session } => {
+                if let Some(sessions) = self.remote.get_mut(&room) {
+                    if sessions.remove(&(node, session)) {
+                        self.send_message(&room, "Someone disconnected", 0);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Handler for `NodeDown` message.
+impl Handler<cluster::NodeDown> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, msg: cluster::NodeDown, _: &mut Context<Self>) {
+        log::info!("cluster node {} is gone", msg.node);
+
+        let mut rooms = Vec::new();
+        for (name, sessions) in &mut self.remote {
+            let before = sessions.len();
+            sessions.retain(|(node, _)| *node != msg.node);
+            for _ in sessions.len()..before {
+                rooms.push(name.to_owned());
             }
         }
-        // send message to other users
+
         for room in rooms {
             self.send_message(&room, "Someone disconnected", 0);
         }
+    }
+}
 
-        self.rooms.entry(name.clone()).or_default().insert(id);
+/// Handler for `PeerConnected` message.
+///
+/// Tell the new peer which sessions are in which room.
+impl Handler<cluster::PeerConnected> for ChatServer {
+    type Result = ();
 
-        self.send_message(&name, "Someone connected", id);
+    fn handle(&mut self, msg: cluster::PeerConnected, _: &mut Context<Self>) {
+        if let Some(cluster) = &self.cluster {
+            for (id, room) in &self.members {
+                cluster.send(&msg.0, room, EventKind::Enter { session: *id });
+            }
+        }
     }
 }
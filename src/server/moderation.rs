@@ -0,0 +1,64 @@
+//! Audit trail of edited and deleted messages. `ChatServer` keeps the text
+//! a message had before it was changed, moderators read it through the
+//! REST API.
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use super::{deserialize_id, room::StoredMessage, serialize_id};
+
+/// How many audit entries are kept, older ones are dropped
+pub const AUDIT_LIMIT: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Edit,
+    Delete,
+}
+
+/// Text that was removed from a room
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+
+    /// When the message was changed
+    pub time: DateTime<Utc>,
+
+    pub room: String,
+
+    #[serde(serialize_with = "serialize_id", deserialize_with = "deserialize_id")]
+    pub message_id: u64,
+
+    pub seq: u64,
+
+    /// Text before the change
+    pub text: String,
+
+    /// Name of the session that made the change
+    pub by: String,
+
+    /// Change was made by a moderator of somebody else's message
+    pub moderator: bool,
+}
+
+impl AuditEntry {
+    pub fn new(
+        action: AuditAction,
+        room: String,
+        old: &StoredMessage,
+        by: String,
+        moderator: bool,
+    ) -> AuditEntry {
+        AuditEntry {
+            action,
+            time: Utc::now(),
+            room,
+            message_id: old.meta.id,
+            seq: old.meta.seq,
+            text: old.text.to_string(),
+            by,
+            moderator,
+        }
+    }
+}
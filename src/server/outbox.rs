@@ -0,0 +1,312 @@
+//! `Outbox` is the bounded outbound queue of a session.
+//!
+//! Only one message per session is in the session mailbox at a time, the
+//! rest waits in the outbox. When the session wrote a message to its peer
+//! and dropped the `Receipt` the next one is delivered, so a slow peer makes
+//! its own outbox grow instead of the mailbox or the write buffer. When the
+//! outbox is full the configured `OverflowPolicy` decides what happens.
+//!
+//! Transient events like typing indicators take a lane of their own. They
+//! neither wait behind queued messages nor count against the capacity, a
+//! session that falls behind only loses the oldest of them.
+
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use actix::prelude::*;
+use bytestring::ByteString;
+
+use super::{Message, MessageMeta, Payload};
+
+/// How many transient events may wait for a session
+const SIGNAL_CAPACITY: usize = 8;
+
+/// What to do when a session outbox is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Drop the oldest queued messages
+    DropOldest,
+
+    /// Drop the oldest queued messages and tell the peer how many it missed
+    Notify,
+
+    /// Disconnect the session
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "notify" => Ok(OverflowPolicy::Notify),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!("unknown overflow policy: {s:?}")),
+        }
+    }
+}
+
+/// Outbox settings shared by all sessions
+#[derive(Debug, Clone)]
+pub struct OutboxConfig {
+    /// How many messages may wait for a session
+    pub capacity: usize,
+
+    /// What to do when `capacity` is reached
+    pub policy: OverflowPolicy,
+}
+
+/// Outbox state, reported by the metrics endpoint
+#[derive(Debug, Clone, Default)]
+pub struct OutboxStats {
+    /// Messages waiting for the session
+    pub queued: usize,
+
+    /// Messages dropped because the outbox was full
+    pub dropped: u64,
+
+    /// Session was disconnected because the outbox was full
+    pub overflowed: bool,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    queue: VecDeque<(Payload, Option<MessageMeta>)>,
+    in_flight: bool,
+    signals: VecDeque<Payload>,
+    signal_in_flight: bool,
+    missed: usize,
+    dropped: u64,
+    overflowed: bool,
+}
+
+struct Inner {
+    addr: Recipient<Message>,
+    config: OutboxConfig,
+    state: Mutex<State>,
+
+    /// Overflows of all outboxes of the server
+    overflows: Arc<AtomicU64>,
+}
+
+/// Bounded outbound queue in front of a session
+#[derive(Clone)]
+pub struct Outbox {
+    inner: Arc<Inner>,
+}
+
+impl fmt::Debug for Outbox {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Outbox")
+            .field("state", &self.inner.state)
+            .finish()
+    }
+}
+
+impl Outbox {
+    pub fn new(
+        addr: Recipient<Message>,
+        config: OutboxConfig,
+        overflows: Arc<AtomicU64>,
+    ) -> Outbox {
+        Outbox {
+            inner: Arc::new(Inner {
+                addr,
+                config,
+                state: Mutex::new(State::default()),
+                overflows,
+            }),
+        }
+    }
+
+    /// Queue message for the session, peer messages have their `meta`
+    pub fn push(&self, payload: impl Into<Payload>, meta: Option<MessageMeta>) {
+        let msg = (payload.into(), meta);
+        let capacity = self.inner.config.capacity;
+
+        let deliver = {
+            let mut state = self.inner.state.lock().unwrap();
+
+            if state.overflowed {
+                return;
+            }
+
+            if !state.in_flight {
+                state.in_flight = true;
+                Some(msg)
+            } else {
+                if state.queue.len() >= capacity {
+                    if state.dropped == 0 {
+                        log::warn!("session outbox is full, peer falls behind");
+                    }
+
+                    match self.inner.config.policy {
+                        OverflowPolicy::DropOldest => {
+                            state.queue.pop_front();
+                        }
+                        OverflowPolicy::Notify => {
+                            state.queue.pop_front();
+                            state.missed += 1;
+                        }
+                        OverflowPolicy::Disconnect => {
+                            state.overflowed = true;
+                            state.queue.clear();
+                        }
+                    }
+                    state.dropped += 1;
+                }
+
+                if state.overflowed {
+                    // the session may wait for its peer, it has to learn
+                    // about the overflow before the current message is done
+                    self.inner.overflows.fetch_add(1, Ordering::Relaxed);
+                    Some((ByteString::from_static("!!! too slow").into(), None))
+                } else {
+                    state.queue.push_back(msg);
+                    None
+                }
+            }
+        };
+
+        if let Some(msg) = deliver {
+            self.deliver(msg, false);
+        }
+    }
+
+    /// Send transient event, it passes queued messages and does not count
+    /// against the capacity. The oldest events are dropped when the session
+    /// falls behind.
+    pub fn signal(&self, payload: impl Into<Payload>) {
+        let payload = payload.into();
+
+        let deliver = {
+            let mut state = self.inner.state.lock().unwrap();
+
+            if state.overflowed {
+                return;
+            }
+
+            if !state.signal_in_flight {
+                state.signal_in_flight = true;
+                Some(payload)
+            } else {
+                if state.signals.len() >= SIGNAL_CAPACITY {
+                    state.signals.pop_front();
+                }
+                state.signals.push_back(payload);
+                None
+            }
+        };
+
+        if let Some(payload) = deliver {
+            self.deliver((payload, None), true);
+        }
+    }
+
+    /// Current state of the outbox
+    pub fn stats(&self) -> OutboxStats {
+        let state = self.inner.state.lock().unwrap();
+
+        OutboxStats {
+            queued: state.queue.len(),
+            dropped: state.dropped,
+            overflowed: state.overflowed,
+        }
+    }
+
+    fn deliver(&self, (payload, meta): (Payload, Option<MessageMeta>), signal: bool) {
+        self.inner.addr.do_send(Message {
+            payload,
+            meta,
+            receipt: Receipt {
+                outbox: self.clone(),
+                signal,
+            },
+        });
+    }
+
+    /// Previous message was handled by the session, send next one
+    fn next(&self) {
+        let next = {
+            let mut state = self.inner.state.lock().unwrap();
+
+            if !self.inner.addr.connected() {
+                // session is gone, nobody will read the queue
+                state.queue.clear();
+                state.in_flight = false;
+                return;
+            }
+
+            let next = if state.missed > 0 {
+                let missed = std::mem::take(&mut state.missed);
+                let text = ByteString::from(format!("!!! you missed {missed} messages"));
+                Some((text.into(), None))
+            } else {
+                state.queue.pop_front()
+            };
+
+            state.in_flight = next.is_some();
+            next
+        };
+
+        if let Some(msg) = next {
+            self.deliver(msg, false);
+        }
+    }
+
+    /// Previous transient event was handled by the session, send next one
+    fn next_signal(&self) {
+        let next = {
+            let mut state = self.inner.state.lock().unwrap();
+
+            if !self.inner.addr.connected() {
+                state.signals.clear();
+                state.signal_in_flight = false;
+                return;
+            }
+
+            let next = state.signals.pop_front();
+            state.signal_in_flight = next.is_some();
+            next
+        };
+
+        if let Some(payload) = next {
+            self.deliver((payload, None), true);
+        }
+    }
+}
+
+/// Attached to every delivered message, releases the next queued message
+/// when the session is done with the current one.
+#[derive(Debug)]
+pub struct Receipt {
+    outbox: Outbox,
+
+    /// Message came through the lane of transient events
+    signal: bool,
+}
+
+impl Receipt {
+    /// Outbox overflowed, the session has to be disconnected
+    pub fn overflowed(&self) -> bool {
+        self.outbox.inner.state.lock().unwrap().overflowed
+    }
+}
+
+impl Drop for Receipt {
+    fn drop(&mut self) {
+        if self.signal {
+            self.outbox.next_signal();
+        } else {
+            self.outbox.next();
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Presence of the users. `ChatServer` keeps one `Presence` per session, a
+//! session is idle when it did not send a message for a while and away
+//! when its user says so with `/away`.
+
+use std::{
+    fmt,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+/// How often `ChatServer` looks for idle sessions
+pub const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Online,
+
+    /// No message for `IDLE_TIMEOUT`
+    Idle,
+
+    /// Set with `/away`
+    Away,
+
+    /// Disconnected, only known for named users
+    Offline,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Online => "online",
+            Status::Idle => "idle",
+            Status::Away => "away",
+            Status::Offline => "offline",
+        })
+    }
+}
+
+/// Presence of a connected session
+#[derive(Debug)]
+pub struct Presence {
+    pub status: Status,
+
+    /// Away message
+    pub reason: Option<String>,
+
+    /// Last message of the session
+    pub last_active: Instant,
+}
+
+impl Presence {
+    pub fn new() -> Presence {
+        Presence {
+            status: Status::Online,
+            reason: None,
+            last_active: Instant::now(),
+        }
+    }
+
+    /// Session sent a message, returns true when it is back from idle
+    pub fn active(&mut self) -> bool {
+        self.last_active = Instant::now();
+
+        if self.status == Status::Idle {
+            self.status = Status::Online;
+            return true;
+        }
+        false
+    }
+
+    /// Returns true when the session just became idle, away sessions stay
+    /// away
+    pub fn check_idle(&mut self, timeout: Duration) -> bool {
+        if self.status == Status::Online && self.last_active.elapsed() > timeout {
+            self.status = Status::Idle;
+            return true;
+        }
+        false
+    }
+
+    /// `/away [reason]`
+    pub fn away(&mut self, reason: Option<String>) {
+        self.status = Status::Away;
+        self.reason = reason;
+    }
+
+    /// `/back`, returns false when the session was not away
+    pub fn back(&mut self) -> bool {
+        if self.status != Status::Away {
+            return false;
+        }
+
+        self.status = Status::Online;
+        self.reason = None;
+        self.last_active = Instant::now();
+        true
+    }
+
+    /// Status line sent to the room
+    pub fn describe(&self, name: &str) -> String {
+        match &self.reason {
+            Some(reason) => format!("{name} is {}: {reason}", self.status),
+            None => format!("{name} is {}", self.status),
+        }
+    }
+}
+
+/// Presence of a user, returned by the REST API
+#[derive(Debug, Serialize)]
+pub struct UserPresence {
+    /// Session name, sessions without name are `anonymous`
+    pub name: String,
+
+    pub status: Status,
+
+    /// Joined room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+
+    /// Away message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+
+    /// Seconds since the last message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub idle_seconds: Option<u64>,
+
+    /// When an offline user disconnected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_seen: Option<DateTime<Utc>>,
+}
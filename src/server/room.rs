@@ -0,0 +1,700 @@
+//! `Room` is an actor. Every chat room runs as its own actor, so broadcasting
+//! in one room does not block the others. Rooms are created by `ChatServer`
+//! and may live on different arbiters.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use actix::prelude::*;
+use bytes::Bytes;
+use bytestring::ByteString;
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+
+use super::{
+    search::{Index, SearchHit, SearchQuery},
+    snapshot::RoomSnapshot,
+    MessageMeta, Outbox, Payload, RoomEvent,
+};
+
+/// How many messages a room keeps in its history
+const HISTORY_LIMIT: usize = 100;
+
+/// Session enters the room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Enter {
+    /// Client ID
+    pub id: usize,
+
+    /// Outbound queue of the session
+    pub outbox: Outbox,
+}
+
+/// Read-only subscriber starts watching the room. It receives room
+/// messages but is not announced to the other users.
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Watch {
+    /// Client ID
+    pub id: usize,
+
+    /// Outbound queue of the subscriber
+    pub outbox: Outbox,
+
+    /// Replay history after this sequence number
+    pub since: Option<u64>,
+}
+
+/// Session leaves the room
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Leave {
+    /// Client ID
+    pub id: usize,
+}
+
+/// Message sent by a peer, it is stored in the room history
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Publish {
+    /// Id of the client session
+    pub id: usize,
+
+    /// User of the session, it owns the message
+    pub author: usize,
+
+    /// Name of the session
+    pub name: String,
+
+    /// Unique id given by the chat server
+    pub message_id: u64,
+
+    /// Time the chat server accepted the message
+    pub time: DateTime<Utc>,
+
+    /// Peer message
+    pub msg: ByteString,
+
+    /// First message of the thread, for replies
+    pub thread: Option<u64>,
+}
+
+/// Opaque data sent by a peer, it is relayed to the sessions of the room
+/// but not stored in the history
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Relay {
+    /// Id of the client session
+    pub id: usize,
+
+    /// Peer data
+    pub data: Bytes,
+}
+
+/// Typing indicator of a session, it is sent to the other sessions of the
+/// room but not stored in the history
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Typing {
+    /// Id of the client session
+    pub id: usize,
+
+    /// Session name
+    pub name: ByteString,
+
+    pub active: bool,
+}
+
+/// First message of the thread of a message and its author, a message
+/// that is no reply starts its own thread
+pub struct ThreadRoot {
+    pub message_id: u64,
+}
+
+impl actix::Message for ThreadRoot {
+    type Result = Result<(u64, usize), String>;
+}
+
+/// Stored messages of the thread of a message
+pub struct Thread {
+    pub message_id: u64,
+}
+
+impl actix::Message for Thread {
+    type Result = Result<Vec<StoredMessage>, String>;
+}
+
+/// Author changes the text of a message
+pub struct Edit {
+    /// User of the client session
+    pub author: usize,
+
+    pub message_id: u64,
+
+    pub text: ByteString,
+}
+
+impl actix::Message for Edit {
+    type Result = Result<StoredMessage, String>;
+}
+
+/// Author or a moderator removes a message
+pub struct Delete {
+    /// User of the client session
+    pub author: usize,
+
+    pub message_id: u64,
+
+    /// Session may delete messages of others
+    pub moderator: bool,
+}
+
+impl actix::Message for Delete {
+    type Result = Result<StoredMessage, String>;
+}
+
+/// Session adds or removes a reaction
+pub struct React {
+    /// User of the client session, a user reacts once with an emoji
+    pub author: usize,
+
+    /// Session name shown with the reaction
+    pub name: ByteString,
+
+    pub message_id: u64,
+
+    pub emoji: ByteString,
+
+    /// `false` removes the reaction
+    pub add: bool,
+}
+
+impl actix::Message for React {
+    type Result = Result<(), String>;
+}
+
+/// How many sessions reacted with an emoji
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ReactionCount {
+    pub emoji: String,
+    pub count: usize,
+}
+
+/// Message kept in the room history
+#[derive(Debug, Clone, Serialize)]
+pub struct StoredMessage {
+    #[serde(flatten)]
+    pub meta: MessageMeta,
+
+    /// User that sent the message, `0` for messages of other nodes
+    #[serde(skip)]
+    pub author: usize,
+
+    /// Name of the author when the message was sent
+    #[serde(rename = "author")]
+    pub name: String,
+
+    #[serde(serialize_with = "serialize_text")]
+    pub text: ByteString,
+
+    /// Users that reacted, by emoji
+    #[serde(
+        skip_serializing_if = "BTreeMap::is_empty",
+        serialize_with = "serialize_reactions"
+    )]
+    pub reactions: BTreeMap<ByteString, HashSet<usize>>,
+}
+
+fn serialize_text<S: Serializer>(text: &ByteString, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(text)
+}
+
+fn serialize_reactions<S: Serializer>(
+    reactions: &BTreeMap<ByteString, HashSet<usize>>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.collect_seq(reactions.iter().map(|(emoji, sessions)| ReactionCount {
+        emoji: emoji.to_string(),
+        count: sessions.len(),
+    }))
+}
+
+impl StoredMessage {
+    /// Text without the `name: ` prefix of named sessions
+    pub fn body(&self) -> &str {
+        self.text
+            .strip_prefix(self.name.as_str())
+            .and_then(|text| text.strip_prefix(": "))
+            .unwrap_or(&self.text)
+    }
+
+    /// Reaction counts in order of the emoji
+    pub fn reactions(&self) -> Vec<ReactionCount> {
+        self.reactions
+            .iter()
+            .map(|(emoji, sessions)| ReactionCount {
+                emoji: emoji.to_string(),
+                count: sessions.len(),
+            })
+            .collect()
+    }
+}
+
+/// Stored messages after `since`, at most `limit` of them
+pub struct History {
+    pub since: u64,
+    pub limit: usize,
+}
+
+impl actix::Message for History {
+    type Result = HistoryPage;
+}
+
+/// Part of the room history
+#[derive(Debug)]
+pub struct HistoryPage {
+    /// Messages in order of their sequence number
+    pub messages: Vec<StoredMessage>,
+
+    /// There are more messages after this page
+    pub more: bool,
+
+    /// Sequence number of the latest message in the room
+    pub seq: u64,
+
+    /// False when messages right after `since` already left the history
+    pub complete: bool,
+}
+
+/// Messages of the history that match the query, best first
+pub struct Search {
+    pub query: SearchQuery,
+}
+
+impl actix::Message for Search {
+    type Result = Vec<SearchHit>;
+}
+
+/// State of the room for a snapshot
+pub struct Save;
+
+impl actix::Message for Save {
+    type Result = RoomSnapshot;
+}
+
+/// Status message from the server, it is not stored in the history
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Notice {
+    /// Status message
+    pub msg: ByteString,
+
+    /// Session that should not receive the message
+    pub skip_id: usize,
+}
+
+/// `Room` holds the sessions that joined it and recent messages.
+///
+/// Every stored message gets the next sequence number of the room.
+/// Sequence numbers are per node, a room on another node counts its own.
+#[derive(Debug)]
+pub struct Room {
+    name: String,
+    sessions: HashMap<usize, Outbox>,
+    watchers: HashMap<usize, Outbox>,
+    history: VecDeque<StoredMessage>,
+    seq: u64,
+    evicted: u64, // latest seq that left the history
+    index: Index,
+}
+
+impl Room {
+    pub fn new(name: String) -> Room {
+        Room {
+            name,
+            sessions: HashMap::new(),
+            watchers: HashMap::new(),
+            history: VecDeque::with_capacity(HISTORY_LIMIT),
+            seq: 0,
+            evicted: 0,
+            index: Index::default(),
+        }
+    }
+
+    /// Room of a snapshot, without sessions
+    pub fn restore(saved: RoomSnapshot) -> Room {
+        let mut room = Room::new(saved.name);
+        room.seq = saved.seq;
+        room.evicted = saved.evicted;
+
+        for saved in saved.history {
+            let stored = StoredMessage::from(saved);
+            room.index.insert(stored.meta.id, stored.body());
+            room.history.push_back(stored);
+        }
+        room
+    }
+
+    /// Position of a message in the history
+    fn find(&self, message_id: u64) -> Result<usize, String> {
+        self.history
+            .iter()
+            .position(|stored| stored.meta.id == message_id)
+            .ok_or_else(|| "message not found".to_owned())
+    }
+
+    /// Send event to the sessions of the room
+    fn send_event(&self, event: RoomEvent, skip_id: usize) {
+        let payload = Payload::Event(event);
+        for (id, outbox) in &self.sessions {
+            if *id != skip_id {
+                outbox.push(payload.clone(), None);
+            }
+        }
+    }
+
+    /// Send message to all users in the room, recipients share the same buffer
+    fn send_message(&self, message: &ByteString, meta: Option<MessageMeta>, skip_id: usize) {
+        for (id, outbox) in self.sessions.iter().chain(&self.watchers) {
+            if *id != skip_id {
+                outbox.push(message.clone(), meta.clone());
+            }
+        }
+    }
+}
+
+impl Actor for Room {
+    type Context = Context<Self>;
+
+    fn started(&mut self, _: &mut Context<Self>) {
+        log::debug!("room {:?} started", self.name);
+    }
+}
+
+/// Handler for Enter message.
+impl Handler<Enter> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: Enter, _: &mut Context<Self>) {
+        self.sessions.insert(msg.id, msg.outbox);
+    }
+}
+
+/// Handler for Watch message.
+///
+/// Missed messages are replayed before the subscriber gets new ones.
+impl Handler<Watch> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: Watch, _: &mut Context<Self>) {
+        if let Some(since) = msg.since {
+            for stored in self.history.iter().filter(|stored| stored.meta.seq > since) {
+                msg.outbox
+                    .push(stored.text.clone(), Some(stored.meta.clone()));
+            }
+        }
+
+        self.watchers.insert(msg.id, msg.outbox);
+    }
+}
+
+/// Handler for Leave message.
+///
+/// Remove session and notify remaining users
+impl Handler<Leave> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: Leave, _: &mut Context<Self>) {
+        self.watchers.remove(&msg.id);
+
+        if self.sessions.remove(&msg.id).is_some() {
+            self.send_message(&ByteString::from_static("Someone disconnected"), None, 0);
+        }
+    }
+}
+
+/// Handler for Publish message.
+impl Handler<Publish> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: Publish, _: &mut Context<Self>) {
+        self.seq += 1;
+        let meta = MessageMeta {
+            id: msg.message_id,
+            time: msg.time,
+            seq: self.seq,
+            edited: None,
+            thread: msg.thread,
+            author: msg.name.as_str().into(),
+        };
+        self.send_message(&msg.msg, Some(meta.clone()), msg.id);
+
+        // the author learns the id to edit, delete or reply to the message
+        if let Some(outbox) = self.sessions.get(&msg.id) {
+            let event = RoomEvent::Sent {
+                id: meta.id,
+                seq: meta.seq,
+                time: meta.time,
+            };
+            outbox.push(Payload::Event(event), None);
+        }
+
+        if self.history.len() == HISTORY_LIMIT {
+            if let Some(evicted) = self.history.pop_front() {
+                self.evicted = evicted.meta.seq;
+                self.index.remove(evicted.meta.id, evicted.body());
+            }
+        }
+        let stored = StoredMessage {
+            meta,
+            author: msg.author,
+            name: msg.name,
+            text: msg.msg,
+            reactions: BTreeMap::new(),
+        };
+        self.index.insert(stored.meta.id, stored.body());
+        self.history.push_back(stored);
+    }
+}
+
+/// Handler for Relay message.
+///
+/// Read-only watchers only receive text, they do not get the data.
+impl Handler<Relay> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: Relay, _: &mut Context<Self>) {
+        for (id, outbox) in &self.sessions {
+            if *id != msg.id {
+                outbox.push(msg.data.clone(), None);
+            }
+        }
+    }
+}
+
+/// Handler for Notice message.
+impl Handler<Notice> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: Notice, _: &mut Context<Self>) {
+        self.send_message(&msg.msg, None, msg.skip_id);
+    }
+}
+
+/// Handler for Typing message.
+impl Handler<Typing> for Room {
+    type Result = ();
+
+    fn handle(&mut self, msg: Typing, _: &mut Context<Self>) {
+        let event = Payload::Event(RoomEvent::Typing {
+            name: msg.name,
+            active: msg.active,
+        });
+        // typing must not hold back or push out chat messages
+        for (id, outbox) in &self.sessions {
+            if *id != msg.id {
+                outbox.signal(event.clone());
+            }
+        }
+    }
+}
+
+/// Handler for History message.
+impl Handler<History> for Room {
+    type Result = MessageResult<History>;
+
+    fn handle(&mut self, msg: History, _: &mut Context<Self>) -> Self::Result {
+        let mut messages = self
+            .history
+            .iter()
+            .filter(|stored| stored.meta.seq > msg.since);
+
+        let page: Vec<_> = messages.by_ref().take(msg.limit).cloned().collect();
+
+        MessageResult(HistoryPage {
+            messages: page,
+            more: messages.next().is_some(),
+            seq: self.seq,
+            complete: self.evicted <= msg.since,
+        })
+    }
+}
+
+/// Handler for Edit message.
+///
+/// Returns the message as it was before the edit.
+impl Handler<Edit> for Room {
+    type Result = Result<StoredMessage, String>;
+
+    fn handle(&mut self, msg: Edit, _: &mut Context<Self>) -> Self::Result {
+        let pos = self.find(msg.message_id)?;
+        let stored = &mut self.history[pos];
+        if stored.author != msg.author {
+            return Err("you can only edit your own messages".to_owned());
+        }
+
+        let old = stored.clone();
+        let time = Utc::now();
+        stored.text = msg.text.clone();
+        stored.meta.edited = Some(time);
+        self.index.remove(old.meta.id, old.body());
+        self.index.insert(old.meta.id, self.history[pos].body());
+
+        self.send_event(
+            RoomEvent::Edited {
+                id: old.meta.id,
+                seq: old.meta.seq,
+                text: msg.text,
+                time,
+            },
+            0,
+        );
+        Ok(old)
+    }
+}
+
+/// Handler for Delete message.
+///
+/// Returns the removed message.
+impl Handler<Delete> for Room {
+    type Result = Result<StoredMessage, String>;
+
+    fn handle(&mut self, msg: Delete, _: &mut Context<Self>) -> Self::Result {
+        let pos = self.find(msg.message_id)?;
+        if self.history[pos].author != msg.author && !msg.moderator {
+            return Err("you can only delete your own messages".to_owned());
+        }
+
+        let removed = self.history.remove(pos).unwrap();
+        self.index.remove(removed.meta.id, removed.body());
+        self.send_event(
+            RoomEvent::Deleted {
+                id: removed.meta.id,
+                seq: removed.meta.seq,
+            },
+            0,
+        );
+        Ok(removed)
+    }
+}
+
+/// Handler for React message.
+///
+/// Reacting twice with the same emoji or removing a missing reaction does
+/// nothing.
+impl Handler<React> for Room {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: React, _: &mut Context<Self>) -> Self::Result {
+        let pos = self.find(msg.message_id)?;
+        let stored = &mut self.history[pos];
+
+        let users = stored.reactions.entry(msg.emoji.clone()).or_default();
+        let changed = if msg.add {
+            users.insert(msg.author)
+        } else {
+            users.remove(&msg.author)
+        };
+        let count = users.len();
+        if count == 0 {
+            stored.reactions.remove(&msg.emoji);
+        }
+
+        if changed {
+            let event = RoomEvent::Reaction {
+                id: stored.meta.id,
+                seq: stored.meta.seq,
+                emoji: msg.emoji,
+                name: msg.name,
+                added: msg.add,
+                count,
+            };
+            self.send_event(event, 0);
+        }
+        Ok(())
+    }
+}
+
+/// Handler for ThreadRoot message.
+impl Handler<ThreadRoot> for Room {
+    type Result = Result<(u64, usize), String>;
+
+    fn handle(&mut self, msg: ThreadRoot, _: &mut Context<Self>) -> Self::Result {
+        let stored = &self.history[self.find(msg.message_id)?];
+
+        let Some(thread) = stored.meta.thread else {
+            return Ok((stored.meta.id, stored.author));
+        };
+        // the first message may already have left the history, its author
+        // joined the thread with the first reply
+        let author = self.find(thread).map_or(0, |pos| self.history[pos].author);
+        Ok((thread, author))
+    }
+}
+
+/// Handler for Thread message.
+impl Handler<Thread> for Room {
+    type Result = Result<Vec<StoredMessage>, String>;
+
+    fn handle(&mut self, msg: Thread, _: &mut Context<Self>) -> Self::Result {
+        let stored = &self.history[self.find(msg.message_id)?];
+        let thread = stored.meta.thread.unwrap_or(stored.meta.id);
+
+        let messages = self
+            .history
+            .iter()
+            .filter(|stored| stored.meta.id == thread || stored.meta.thread == Some(thread))
+            .cloned()
+            .collect();
+        Ok(messages)
+    }
+}
+
+/// Handler for Search message.
+impl Handler<Search> for Room {
+    type Result = MessageResult<Search>;
+
+    fn handle(&mut self, msg: Search, _: &mut Context<Self>) -> Self::Result {
+        let query = msg.query;
+        let scores = (!query.words.is_empty()).then(|| self.index.search(&query.words));
+
+        let mut hits: Vec<SearchHit> = self
+            .history
+            .iter()
+            .filter(|stored| query.filter(stored))
+            .filter_map(|stored| {
+                let score = match &scores {
+                    Some(scores) => *scores.get(&stored.meta.id)?,
+                    None => 0.0,
+                };
+                Some(SearchHit {
+                    message: stored.clone(),
+                    score,
+                })
+            })
+            .collect();
+
+        hits.sort_by(|a, b| {
+            b.score
+                .total_cmp(&a.score)
+                .then(b.message.meta.seq.cmp(&a.message.meta.seq))
+        });
+        hits.truncate(query.limit);
+        MessageResult(hits)
+    }
+}
+
+/// Handler for Save message.
+impl Handler<Save> for Room {
+    type Result = MessageResult<Save>;
+
+    fn handle(&mut self, _: Save, _: &mut Context<Self>) -> Self::Result {
+        MessageResult(RoomSnapshot {
+            name: self.name.clone(),
+            seq: self.seq,
+            evicted: self.evicted,
+            history: self.history.iter().map(Into::into).collect(),
+            attachments: Vec::new(),
+        })
+    }
+}
@@ -0,0 +1,183 @@
+//! Full-text search of the room history. Every `Room` keeps an inverted
+//! index of its stored messages, results are ranked by tf-idf and newer
+//! messages win ties. Messages that leave the history of the room leave
+//! the index too, so only the last `HISTORY_LIMIT` messages are found.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+
+use super::room::StoredMessage;
+
+/// Results when the query does not ask for a number
+pub const DEFAULT_LIMIT: usize = 20;
+
+/// Most results of one search
+pub const MAX_LIMIT: usize = 100;
+
+/// Longer words are not indexed, they are not searched for and would copy
+/// the whole text of a message
+const MAX_WORD_LEN: usize = 64;
+
+/// Lowercase words of a text
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Lowercase words of a text that are indexed
+fn indexed(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty() && word.len() <= MAX_WORD_LEN)
+        .map(str::to_lowercase)
+}
+
+/// Words of the stored messages and the messages that contain them
+#[derive(Debug, Default)]
+pub struct Index {
+    postings: HashMap<String, HashMap<u64, u32>>, // word -> message id -> count
+    messages: usize,
+}
+
+impl Index {
+    pub fn insert(&mut self, id: u64, text: &str) {
+        for word in indexed(text) {
+            *self
+                .postings
+                .entry(word)
+                .or_default()
+                .entry(id)
+                .or_default() += 1;
+        }
+        self.messages += 1;
+    }
+
+    /// Remove a message, `text` is the text it was inserted with
+    pub fn remove(&mut self, id: u64, text: &str) {
+        for word in indexed(text) {
+            if let Some(ids) = self.postings.get_mut(&word) {
+                ids.remove(&id);
+                if ids.is_empty() {
+                    self.postings.remove(&word);
+                }
+            }
+        }
+        self.messages = self.messages.saturating_sub(1);
+    }
+
+    /// Scores of the messages that contain every word
+    pub fn search(&self, words: &[String]) -> HashMap<u64, f64> {
+        let mut scores: Option<HashMap<u64, f64>> = None;
+
+        for word in words {
+            let Some(ids) = self.postings.get(word) else {
+                return HashMap::new();
+            };
+            let idf = (1.0 + self.messages as f64 / ids.len() as f64).ln();
+
+            let matches = ids.iter().filter_map(|(id, count)| {
+                let prev = match &scores {
+                    Some(scores) => *scores.get(id)?,
+                    None => 0.0,
+                };
+                Some((*id, prev + (1.0 + f64::from(*count).ln()) * idf))
+            });
+            scores = Some(matches.collect());
+        }
+        scores.unwrap_or_default()
+    }
+}
+
+/// What to look for in a room
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    /// Every word has to be in the message
+    pub words: Vec<String>,
+
+    /// Name of the author
+    pub author: Option<String>,
+
+    /// Messages sent at or after
+    pub from: Option<DateTime<Utc>>,
+
+    /// Messages sent before
+    pub to: Option<DateTime<Utc>>,
+
+    pub limit: usize,
+}
+
+impl SearchQuery {
+    /// Words and the filters `author:name`, `after:date` and `before:date`
+    /// of `/search`. Dates are RFC 3339 times or days like `2024-05-01`,
+    /// `before` a day includes that day.
+    pub fn parse(line: &str) -> Result<SearchQuery, String> {
+        let mut query = SearchQuery {
+            words: Vec::new(),
+            author: None,
+            from: None,
+            to: None,
+            limit: DEFAULT_LIMIT,
+        };
+
+        for part in line.split_whitespace() {
+            if let Some(author) = part.strip_prefix("author:") {
+                query.author = Some(author.to_owned());
+            } else if let Some(from) = part.strip_prefix("after:") {
+                query.from = Some(parse_time(from, false)?);
+            } else if let Some(to) = part.strip_prefix("before:") {
+                query.to = Some(parse_time(to, true)?);
+            } else {
+                query.words.extend(tokenize(part));
+            }
+        }
+        Ok(query)
+    }
+
+    /// At least one and at most `MAX_LIMIT` results
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit.clamp(1, MAX_LIMIT);
+    }
+
+    /// Query has nothing to look for
+    pub fn is_empty(&self) -> bool {
+        self.words.is_empty() && self.author.is_none() && self.from.is_none() && self.to.is_none()
+    }
+
+    /// Message passes the author and date filters
+    pub fn filter(&self, stored: &StoredMessage) -> bool {
+        self.author
+            .as_ref()
+            .is_none_or(|author| author.eq_ignore_ascii_case(&stored.name))
+            && self.from.is_none_or(|from| stored.meta.time >= from)
+            && self.to.is_none_or(|to| stored.meta.time < to)
+    }
+}
+
+/// RFC 3339 time or a day, `end` of a day is the start of the next one
+pub fn parse_time(value: &str, end: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    let day = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("invalid date {value:?}, use 2024-05-01 or RFC 3339"))?;
+    let day = if end {
+        day.succ_opt()
+            .ok_or_else(|| format!("invalid date {value:?}"))?
+    } else {
+        day
+    };
+    Ok(day.and_hms_opt(0, 0, 0).unwrap().and_utc())
+}
+
+/// Message found by a search
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchHit {
+    #[serde(flatten)]
+    pub message: StoredMessage,
+
+    /// Higher is better
+    pub score: f64,
+}
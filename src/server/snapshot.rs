@@ -0,0 +1,227 @@
+//! Snapshot of the `ChatServer` state, so rooms and their history survive a
+//! restart. The snapshot is a versioned JSON file, it is written to a
+//! temporary file first and renamed, so a crash never leaves half a
+//! snapshot behind. Snapshots are opt-in, see `SNAPSHOT_PATH`.
+//!
+//! Sessions, moderators and thread followers belong to connections and are
+//! not kept. Tokens are, so users can resume after the restart.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    io::{self, BufWriter},
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use bytestring::ByteString;
+use chrono::{DateTime, Utc};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use super::{room::StoredMessage, Attachment, AuditEntry, Mention, MessageMeta};
+
+/// Format of the snapshot file, files of other versions are not restored
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    /// Snapshot file
+    pub path: PathBuf,
+
+    /// How often a snapshot is taken, never when zero
+    pub interval: Duration,
+}
+
+/// Everything `ChatServer` restores on startup
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub version: u32,
+
+    /// When the snapshot was taken
+    pub time: DateTime<Utc>,
+
+    pub rooms: Vec<RoomSnapshot>,
+
+    /// Edited and deleted messages, oldest first
+    pub audit: Vec<AuditEntry>,
+
+    /// Disconnect times of named users
+    pub last_seen: HashMap<String, DateTime<Utc>>,
+
+    /// Users by token
+    #[serde(default)]
+    pub tokens: HashMap<String, usize>,
+
+    /// Mentions of offline users
+    #[serde(default)]
+    pub mentions: HashMap<usize, Vec<Mention>>,
+
+    /// Offline users with a token by their last name
+    #[serde(default)]
+    pub offline: HashMap<String, usize>,
+}
+
+impl Snapshot {
+    /// Number of stored messages in all rooms
+    pub fn messages(&self) -> usize {
+        self.rooms.iter().map(|room| room.history.len()).sum()
+    }
+}
+
+/// Written snapshot, returned to the admin that asked for it
+#[derive(Debug, Serialize)]
+pub struct SnapshotInfo {
+    pub path: String,
+    pub time: DateTime<Utc>,
+    pub rooms: usize,
+    pub messages: usize,
+}
+
+/// State of one room
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    pub name: String,
+
+    /// Latest sequence number, the room goes on counting from here
+    pub seq: u64,
+
+    /// Latest sequence number that left the history
+    pub evicted: u64,
+
+    pub history: Vec<SavedMessage>,
+
+    /// Files shared in the room
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+/// Stored message with the users that reacted to it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SavedMessage {
+    #[serde(flatten)]
+    pub meta: MessageMeta,
+
+    pub author: usize,
+
+    pub name: String,
+
+    pub text: String,
+
+    #[serde(default)]
+    pub reactions: BTreeMap<String, HashSet<usize>>,
+}
+
+impl From<&StoredMessage> for SavedMessage {
+    fn from(stored: &StoredMessage) -> SavedMessage {
+        SavedMessage {
+            meta: stored.meta.clone(),
+            author: stored.author,
+            name: stored.name.clone(),
+            text: stored.text.to_string(),
+            reactions: stored
+                .reactions
+                .iter()
+                .map(|(emoji, sessions)| (emoji.to_string(), sessions.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl From<SavedMessage> for StoredMessage {
+    fn from(saved: SavedMessage) -> StoredMessage {
+        StoredMessage {
+            meta: MessageMeta {
+                author: saved.name.as_str().into(),
+                ..saved.meta
+            },
+            author: saved.author,
+            name: saved.name,
+            text: saved.text.into(),
+            reactions: saved
+                .reactions
+                .into_iter()
+                .map(|(emoji, sessions)| (ByteString::from(emoji), sessions))
+                .collect(),
+        }
+    }
+}
+
+/// Write the snapshot next to `path` and move it there. `written` is the
+/// time of the last written snapshot, an older snapshot is not written.
+pub fn save(path: &Path, snapshot: &Snapshot, written: &Mutex<DateTime<Utc>>) -> io::Result<()> {
+    let mut written = written.lock().unwrap_or_else(PoisonError::into_inner);
+    if *written >= snapshot.time {
+        log::debug!("snapshot of {} is older than the last one", snapshot.time);
+        return Ok(());
+    }
+
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(format!(".{:016x}.tmp", rand::thread_rng().gen::<u64>()));
+    let tmp = PathBuf::from(tmp);
+
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir)?;
+    }
+
+    let res = (|| {
+        let mut file = BufWriter::new(create_private(&tmp)?);
+        serde_json::to_writer(&mut file, snapshot)?;
+        let file = file.into_inner().map_err(io::IntoInnerError::into_error)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)
+    })();
+    if res.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    res?;
+
+    *written = snapshot.time;
+    Ok(())
+}
+
+/// New file that only the owner may read, the snapshot holds the tokens of
+/// the users
+fn create_private(path: &Path) -> io::Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
+}
+
+/// Read the snapshot, `None` when there is none yet. A file that can not be
+/// restored is moved to `<path>.broken`, so the next snapshot does not
+/// overwrite it.
+pub fn load(path: &Path) -> Option<Snapshot> {
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return None,
+        Err(err) => {
+            log::error!("can not read snapshot {}: {err}", path.display());
+            return None;
+        }
+    };
+
+    let err = match serde_json::from_slice::<Snapshot>(&data) {
+        Ok(snapshot) if snapshot.version == SNAPSHOT_VERSION => return Some(snapshot),
+        Ok(snapshot) => format!("unsupported version {}", snapshot.version),
+        Err(err) => err.to_string(),
+    };
+
+    let mut broken = path.as_os_str().to_owned();
+    broken.push(".broken");
+    log::error!(
+        "can not restore snapshot {}: {err}, moving it to {}",
+        path.display(),
+        broken.to_string_lossy()
+    );
+    if let Err(err) = fs::rename(path, &broken) {
+        log::error!("can not move snapshot: {err}");
+    }
+    None
+}
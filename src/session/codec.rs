@@ -0,0 +1,454 @@
+//! Wire formats of websocket chat sessions. The client picks one with
+//! `Sec-WebSocket-Protocol`, clients that offer no subprotocol get the
+//! legacy text protocol.
+
+use actix_web::{http::header, HttpRequest};
+use bytes::Bytes;
+use bytestring::ByteString;
+use chrono::{DateTime, Utc};
+use prost::Message as _;
+use serde::{Deserialize, Serialize};
+
+use super::proto;
+use crate::server::{MessageMeta, Payload, ReactionCount, RoomEvent};
+
+/// Wire format of a websocket session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// `chat.text.v1`, plain text frames and `/commands`
+    Text,
+
+    /// `chat.json.v1`, one JSON object per text frame
+    Json,
+
+    /// `chat.proto.v1`, messages of `proto/chat.proto` in binary frames
+    Proto,
+}
+
+impl Codec {
+    /// Supported subprotocols
+    pub const PROTOCOLS: [&'static str; 3] = ["chat.text.v1", "chat.json.v1", "chat.proto.v1"];
+
+    pub fn from_protocol(name: &str) -> Option<Codec> {
+        match name {
+            "chat.text.v1" => Some(Codec::Text),
+            "chat.json.v1" => Some(Codec::Json),
+            "chat.proto.v1" => Some(Codec::Proto),
+            _ => None,
+        }
+    }
+
+    pub fn protocol(self) -> &'static str {
+        match self {
+            Codec::Text => "chat.text.v1",
+            Codec::Json => "chat.json.v1",
+            Codec::Proto => "chat.proto.v1",
+        }
+    }
+
+    /// Pick the first subprotocol offered by the client that we support.
+    ///
+    /// Returns `Ok(None)` when the client offers none, and the offer when
+    /// nothing in it is supported.
+    pub fn negotiate(req: &HttpRequest) -> Result<Option<Codec>, String> {
+        let mut offered = req
+            .headers()
+            .get_all(header::SEC_WEBSOCKET_PROTOCOL)
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .peekable();
+
+        if offered.peek().is_none() {
+            return Ok(None);
+        }
+
+        let offered: Vec<&str> = offered.collect();
+        offered
+            .iter()
+            .find_map(|name| Codec::from_protocol(name))
+            .map(Some)
+            .ok_or_else(|| offered.join(", "))
+    }
+
+    /// Encode reply for the peer, it is sent as text or binary frame
+    pub fn encode(self, reply: &Reply<'_>) -> Payload {
+        match self {
+            Codec::Text => reply.line().into(),
+            Codec::Json => ByteString::from(serde_json::to_string(reply).unwrap()).into(),
+            Codec::Proto => match reply {
+                Reply::Message {
+                    text,
+                    meta,
+                    reactions,
+                } => proto::ServerFrame::message(text, meta, reactions),
+                Reply::Notice { text } => proto::ServerFrame::notice(text),
+                Reply::Rooms { rooms } => proto::ServerFrame::rooms(rooms.clone()),
+                Reply::Typing { name, active } => proto::ServerFrame::typing(name, *active),
+                Reply::Edited {
+                    id,
+                    seq,
+                    text,
+                    time,
+                } => proto::ServerFrame::edited(*id, *seq, text, time),
+                Reply::Deleted { id, seq } => proto::ServerFrame::deleted(*id, *seq),
+                Reply::Sent { id, seq, time } => proto::ServerFrame::sent(*id, *seq, time),
+                Reply::Reaction {
+                    id,
+                    seq,
+                    emoji,
+                    name,
+                    added,
+                    count,
+                } => proto::ServerFrame::reaction(*id, *seq, emoji, name, *added, *count),
+                Reply::Thread {
+                    room,
+                    thread,
+                    id,
+                    text,
+                } => proto::ServerFrame::thread(room, *thread, *id, text),
+                Reply::Mention {
+                    room,
+                    id,
+                    by,
+                    text,
+                    time,
+                } => proto::ServerFrame::mention(room, *id, by, text, time),
+                Reply::CaughtUp { seq, complete } => proto::ServerFrame::caught_up(*seq, *complete),
+                Reply::Error { text } => proto::ServerFrame::error(text),
+            }
+            .into(),
+        }
+    }
+}
+
+/// Request of a structured client, same as the text commands
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Request {
+    /// some message
+    Message { text: String },
+
+    /// `/list`
+    List,
+
+    /// `/join name`
+    Join { room: String },
+
+    /// `/name name`
+    Name { name: String },
+
+    /// `/typing start|stop`
+    Typing { active: bool },
+
+    /// `/sync seq`
+    Sync {
+        #[serde(default)]
+        since: u64,
+    },
+
+    /// `/edit id text`, the id is a string like in `message`
+    Edit { id: String, text: String },
+
+    /// `/delete id`
+    Delete { id: String },
+
+    /// `/react id emoji`
+    React { id: String, emoji: String },
+
+    /// `/unreact id emoji`
+    Unreact { id: String, emoji: String },
+
+    /// `/mod token`
+    Mod { token: String },
+
+    /// `/resume token`
+    Resume { token: String },
+
+    /// `/reply id text`
+    Reply { id: String, text: String },
+
+    /// `/thread id`
+    Thread { id: String },
+
+    /// `/follow id`
+    Follow { id: String },
+
+    /// `/unfollow id`
+    Unfollow { id: String },
+
+    /// `/search query`
+    Search { query: String },
+}
+
+impl Request {
+    pub fn from_json(text: &str) -> Result<Request, serde_json::Error> {
+        serde_json::from_str(text)
+    }
+
+    /// Decode protobuf frame, a frame without command is `None`
+    pub fn from_proto(data: Bytes) -> Result<Option<Request>, prost::DecodeError> {
+        use proto::client_frame::Frame;
+
+        let command = proto::ClientFrame::decode(data)?
+            .frame
+            .map(|frame| match frame {
+                Frame::Text(text) => Request::Message { text },
+                Frame::List(_) => Request::List,
+                Frame::Join(room) => Request::Join { room },
+                Frame::Name(name) => Request::Name { name },
+                Frame::Typing(active) => Request::Typing { active },
+                Frame::Sync(since) => Request::Sync { since },
+                Frame::Edit(edit) => Request::Edit {
+                    id: edit.id.to_string(),
+                    text: edit.text,
+                },
+                Frame::Delete(id) => Request::Delete { id: id.to_string() },
+                Frame::Mod(token) => Request::Mod { token },
+                Frame::Resume(token) => Request::Resume { token },
+                Frame::React(reaction) => Request::React {
+                    id: reaction.id.to_string(),
+                    emoji: reaction.emoji,
+                },
+                Frame::Unreact(reaction) => Request::Unreact {
+                    id: reaction.id.to_string(),
+                    emoji: reaction.emoji,
+                },
+                Frame::Reply(reply) => Request::Reply {
+                    id: reply.parent.to_string(),
+                    text: reply.text,
+                },
+                Frame::Thread(id) => Request::Thread { id: id.to_string() },
+                Frame::Follow(id) => Request::Follow { id: id.to_string() },
+                Frame::Unfollow(id) => Request::Unfollow { id: id.to_string() },
+                Frame::Search(query) => Request::Search { query },
+            });
+
+        Ok(command)
+    }
+}
+
+/// Sent to a structured client
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Reply<'a> {
+    /// Room message
+    Message {
+        text: &'a str,
+
+        #[serde(flatten)]
+        meta: MessageMeta,
+
+        /// Reaction counts, only replayed messages have them
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        reactions: Vec<ReactionCount>,
+    },
+
+    /// Status message
+    Notice {
+        text: &'a str,
+    },
+
+    Rooms {
+        rooms: Vec<String>,
+    },
+
+    /// Room member started or stopped typing
+    Typing {
+        name: &'a str,
+        active: bool,
+    },
+
+    /// Author changed the text of a message
+    Edited {
+        #[serde(serialize_with = "crate::server::serialize_id")]
+        id: u64,
+        seq: u64,
+        text: &'a str,
+        time: DateTime<Utc>,
+    },
+
+    /// Message was deleted
+    Deleted {
+        #[serde(serialize_with = "crate::server::serialize_id")]
+        id: u64,
+        seq: u64,
+    },
+
+    /// Reaction was added or removed, `count` is the new number of
+    /// reactions with the emoji
+    Reaction {
+        #[serde(serialize_with = "crate::server::serialize_id")]
+        id: u64,
+        seq: u64,
+        emoji: &'a str,
+        name: &'a str,
+        added: bool,
+        count: usize,
+    },
+
+    /// Reply in a followed thread of another room
+    Thread {
+        room: &'a str,
+        #[serde(serialize_with = "crate::server::serialize_id")]
+        thread: u64,
+        #[serde(serialize_with = "crate::server::serialize_id")]
+        id: u64,
+        text: &'a str,
+    },
+
+    /// Message that mentions the peer, in any room
+    Mention {
+        room: &'a str,
+        #[serde(serialize_with = "crate::server::serialize_id")]
+        id: u64,
+        by: &'a str,
+        text: &'a str,
+        time: DateTime<Utc>,
+    },
+
+    /// Message of the peer was accepted
+    Sent {
+        #[serde(serialize_with = "crate::server::serialize_id")]
+        id: u64,
+        seq: u64,
+        time: DateTime<Utc>,
+    },
+
+    /// History replay reached the latest message of the room
+    CaughtUp {
+        seq: u64,
+        complete: bool,
+    },
+
+    /// Invalid command
+    Error {
+        text: &'a str,
+    },
+}
+
+impl Reply<'_> {
+    /// Reply as line of the text protocol
+    pub fn line(&self) -> ByteString {
+        match self {
+            Reply::Message {
+                text,
+                meta,
+                reactions,
+            } => {
+                let mut line = format!("[{}] {text}", meta.id);
+                if !reactions.is_empty() {
+                    let counts: Vec<_> = reactions
+                        .iter()
+                        .map(|reaction| format!("{} {}", reaction.emoji, reaction.count))
+                        .collect();
+                    line.push_str(&format!(" ({})", counts.join(", ")));
+                }
+                ByteString::from(line)
+            }
+            Reply::Notice { text } => ByteString::from(*text),
+            Reply::Rooms { rooms } => ByteString::from(rooms.join("\n")),
+            Reply::Edited { id, text, .. } => {
+                ByteString::from(format!("message {id} edited: {text}"))
+            }
+            Reply::Deleted { id, .. } => ByteString::from(format!("message {id} deleted")),
+            Reply::Sent { id, .. } => ByteString::from(format!("sent [{id}]")),
+            Reply::Reaction {
+                id,
+                emoji,
+                name,
+                added,
+                count,
+                ..
+            } => {
+                let action = if *added { "reacted" } else { "removed" };
+                ByteString::from(format!("{name} {action} {emoji} on message {id} ({count})"))
+            }
+            Reply::Typing { name, active } => {
+                let state = if *active {
+                    "is typing"
+                } else {
+                    "stopped typing"
+                };
+                ByteString::from(format!("{name} {state}"))
+            }
+            Reply::Thread {
+                room, thread, text, ..
+            } => ByteString::from(format!("new reply in thread {thread} of {room}: {text}")),
+            Reply::Mention { room, by, text, .. } => {
+                ByteString::from(format!("{by} mentioned you in {room}: {text}"))
+            }
+            Reply::CaughtUp { seq, complete } => {
+                let gone = if *complete {
+                    ""
+                } else {
+                    ", older messages are gone"
+                };
+                ByteString::from(format!("caught up at {seq}{gone}"))
+            }
+            Reply::Error { text } => ByteString::from(format!("!!! {text}")),
+        }
+    }
+}
+
+impl<'a> From<&'a RoomEvent> for Reply<'a> {
+    fn from(event: &'a RoomEvent) -> Reply<'a> {
+        match event {
+            RoomEvent::Typing { name, active } => Reply::Typing {
+                name,
+                active: *active,
+            },
+            RoomEvent::Edited {
+                id,
+                seq,
+                text,
+                time,
+            } => Reply::Edited {
+                id: *id,
+                seq: *seq,
+                text,
+                time: *time,
+            },
+            RoomEvent::Deleted { id, seq } => Reply::Deleted { id: *id, seq: *seq },
+            RoomEvent::Sent { id, seq, time } => Reply::Sent {
+                id: *id,
+                seq: *seq,
+                time: *time,
+            },
+            RoomEvent::Reaction {
+                id,
+                seq,
+                emoji,
+                name,
+                added,
+                count,
+            } => Reply::Reaction {
+                id: *id,
+                seq: *seq,
+                emoji,
+                name,
+                added: *added,
+                count: *count,
+            },
+            RoomEvent::Thread {
+                room,
+                thread,
+                id,
+                text,
+            } => Reply::Thread {
+                room,
+                thread: *thread,
+                id: *id,
+                text,
+            },
+            RoomEvent::Mention(mention) => Reply::Mention {
+                room: &mention.room,
+                id: mention.id,
+                by: &mention.by,
+                text: &mention.text,
+                time: mention.time,
+            },
+        }
+    }
+}
@@ -0,0 +1,1052 @@
+//! `/commands` of the chat sessions. Built-in commands are registered once
+//! in `Registry::new`, every session type can add its own commands to its
+//! registry. `/help` is generated from the registry.
+
+use std::str::SplitWhitespace;
+
+use actix::prelude::*;
+
+use super::{codec, Session};
+use crate::server::{self, StoredMessage};
+
+/// How many messages `/sync` fetches from the room at once
+const SYNC_PAGE: usize = 50;
+
+/// Longest reaction in bytes
+const MAX_REACTION_LEN: usize = 32;
+
+/// Session state the commands work with
+pub trait ChatSession: Session {
+    /// Joined room
+    fn room(&self) -> &str;
+
+    fn set_room(&mut self, room: &str);
+
+    /// Peer name
+    fn name(&self) -> Option<&str>;
+
+    fn set_name(&mut self, name: &str);
+
+    /// Send status message to peer
+    fn notice(&mut self, text: &str, ctx: &mut Self::Context);
+
+    /// Tell peer its command is invalid
+    fn error(&mut self, text: &str, ctx: &mut Self::Context);
+
+    /// Send room list to peer
+    fn rooms(&mut self, rooms: Vec<String>, ctx: &mut Self::Context) {
+        for room in rooms {
+            self.notice(&room, ctx);
+        }
+    }
+
+    /// Send a message of the room history to peer
+    fn message(&mut self, stored: &StoredMessage, ctx: &mut Self::Context) {
+        let reply = codec::Reply::Message {
+            text: &stored.text,
+            meta: stored.meta.clone(),
+            reactions: stored.reactions(),
+        };
+        self.notice(&reply.line(), ctx);
+    }
+
+    /// History replay reached the latest message `seq` of the room,
+    /// `complete` is false when some messages already left the history
+    fn caught_up(&mut self, seq: u64, complete: bool, ctx: &mut Self::Context) {
+        if complete {
+            self.notice(&format!("caught up at {seq}"), ctx);
+        } else {
+            self.notice(&format!("caught up at {seq}, older messages are gone"), ctx);
+        }
+    }
+
+    /// Commands of this session type
+    fn commands() -> &'static Registry<Self>;
+}
+
+/// Arguments of a command, the text after the command name
+#[derive(Debug, Clone, Copy)]
+pub struct Args<'a> {
+    line: &'a str,
+}
+
+impl<'a> Args<'a> {
+    pub fn new(line: &'a str) -> Args<'a> {
+        Args { line: line.trim() }
+    }
+
+    /// Whole argument text, `None` when there are no arguments
+    pub fn rest(&self) -> Option<&'a str> {
+        Some(self.line).filter(|line| !line.is_empty())
+    }
+
+    /// Arguments split by whitespace
+    pub fn iter(&self) -> SplitWhitespace<'a> {
+        self.line.split_whitespace()
+    }
+}
+
+/// A `/command` a session understands
+pub trait Command<S: ChatSession>: Send + Sync {
+    /// Name without the slash
+    fn name(&self) -> &'static str;
+
+    /// Arguments shown by `/help`
+    fn usage(&self) -> &'static str {
+        ""
+    }
+
+    /// Description shown by `/help`
+    fn help(&self) -> &'static str;
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context);
+}
+
+/// Commands of a session type
+pub struct Registry<S> {
+    commands: Vec<Box<dyn Command<S>>>,
+}
+
+impl<S> Registry<S>
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    /// Registry with the built-in commands
+    pub fn new() -> Registry<S> {
+        Registry {
+            commands: Vec::new(),
+        }
+        .with(Help)
+        .with(List)
+        .with(Join)
+        .with(Name)
+        .with(Who)
+        .with(SyncRoom)
+        .with(Search)
+        .with(Edit)
+        .with(Delete)
+        .with(React)
+        .with(Unreact)
+        .with(Reply)
+        .with(Thread)
+        .with(Follow)
+        .with(Unfollow)
+        .with(Moderate)
+        .with(Token)
+        .with(Resume)
+        .with(Away)
+        .with(Back)
+        .with(Ping)
+    }
+}
+
+impl<S> Default for Registry<S>
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn default() -> Registry<S> {
+        Registry::new()
+    }
+}
+
+impl<S: ChatSession> Registry<S> {
+    /// Add a command, it replaces a command with the same name
+    pub fn with(mut self, command: impl Command<S> + 'static) -> Registry<S> {
+        self.commands.retain(|cmd| cmd.name() != command.name());
+        self.commands.push(Box::new(command));
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn Command<S>> {
+        self.commands
+            .iter()
+            .find(|cmd| cmd.name() == name)
+            .map(|cmd| &**cmd)
+    }
+
+    /// Run command by name
+    pub fn call(&self, name: &str, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        match self.get(name) {
+            Some(command) => command.run(args, session, ctx),
+            None => session.error(&format!("unknown command: /{name}"), ctx),
+        }
+    }
+
+    /// Run a `/command args` line
+    pub fn run(&self, line: &str, session: &mut S, ctx: &mut S::Context) {
+        let line = line.trim().trim_start_matches('/');
+        let (name, args) = line.split_once(' ').unwrap_or((line, ""));
+
+        self.call(name, Args::new(args), session, ctx);
+    }
+
+    /// One line per command
+    pub fn help(&self) -> Vec<String> {
+        self.commands
+            .iter()
+            .map(|cmd| match cmd.usage() {
+                "" => format!("/{} - {}", cmd.name(), cmd.help()),
+                usage => format!("/{} {usage} - {}", cmd.name(), cmd.help()),
+            })
+            .collect()
+    }
+}
+
+/// `/help`
+pub struct Help;
+
+impl<S: ChatSession> Command<S> for Help {
+    fn name(&self) -> &'static str {
+        "help"
+    }
+
+    fn help(&self) -> &'static str {
+        "list commands"
+    }
+
+    fn run(&self, _: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        for line in S::commands().help() {
+            session.notice(&line, ctx);
+        }
+    }
+}
+
+/// `/list`
+pub struct List;
+
+impl<S> Command<S> for List
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "list"
+    }
+
+    fn help(&self) -> &'static str {
+        "list all available rooms"
+    }
+
+    fn run(&self, _: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        // Send ListRooms message to chat server and wait for
+        // response
+        session
+            .server()
+            .send(server::ListRooms)
+            .into_actor(session)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(rooms) => act.rooms(rooms, ctx),
+                    _ => log::error!("/list: chat server is gone"),
+                }
+                fut::ready(())
+            })
+            // .wait(ctx) pauses all events in context,
+            // so actor wont receive any new messages until it get list
+            // of rooms back
+            .wait(ctx)
+    }
+}
+
+/// `/join name`
+pub struct Join;
+
+impl<S: ChatSession> Command<S> for Join {
+    fn name(&self) -> &'static str {
+        "join"
+    }
+
+    fn usage(&self) -> &'static str {
+        "name"
+    }
+
+    fn help(&self) -> &'static str {
+        "join room, if room does not exist, create new one"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let Some(room) = args.rest() else {
+            session.error("room name is required", ctx);
+            return;
+        };
+
+        session.set_room(room);
+        session.server().do_send(server::Join {
+            id: session.id(),
+            name: room.to_owned(),
+        });
+
+        session.notice("joined", ctx);
+    }
+}
+
+/// `/name name`
+pub struct Name;
+
+impl<S: ChatSession> Command<S> for Name {
+    fn name(&self) -> &'static str {
+        "name"
+    }
+
+    fn usage(&self) -> &'static str {
+        "name"
+    }
+
+    fn help(&self) -> &'static str {
+        "set session name"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let Some(name) = args.rest() else {
+            session.error("name is required", ctx);
+            return;
+        };
+
+        session.set_name(name);
+        session.server().do_send(server::SetName {
+            id: session.id(),
+            name: name.to_owned(),
+        });
+    }
+}
+
+/// `/who`
+pub struct Who;
+
+impl<S> Command<S> for Who
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "who"
+    }
+
+    fn help(&self) -> &'static str {
+        "list users in the room, their presence and round-trip time"
+    }
+
+    fn run(&self, _: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        session
+            .server()
+            .send(server::Members {
+                room: session.room().to_owned(),
+            })
+            .into_actor(session)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(members) => {
+                        for member in members {
+                            let line = match member.rtt {
+                                Some(rtt) => format!(
+                                    "{} - {} ({} ms)",
+                                    member.name,
+                                    member.status,
+                                    rtt.as_millis()
+                                ),
+                                None => format!("{} - {}", member.name, member.status),
+                            };
+                            act.notice(&line, ctx);
+                        }
+                    }
+                    _ => log::error!("/who: chat server is gone"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+}
+
+/// `/sync [seq]`, replay the messages of the room after `seq`
+pub struct SyncRoom;
+
+impl<S> Command<S> for SyncRoom
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "sync"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[seq]"
+    }
+
+    fn help(&self) -> &'static str {
+        "get the messages of the room after seq"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let since = match args.iter().next().map(str::parse::<u64>) {
+            None => 0,
+            Some(Ok(since)) => since,
+            Some(Err(_)) => {
+                session.error("seq must be a number", ctx);
+                return;
+            }
+        };
+
+        sync_page(session, session.room().to_owned(), since, None, ctx);
+    }
+}
+
+/// `/search words`, ranked search of the stored messages of the room
+pub struct Search;
+
+impl<S> Command<S> for Search
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "search"
+    }
+
+    fn usage(&self) -> &'static str {
+        "words [author:name] [after:date] [before:date]"
+    }
+
+    fn help(&self) -> &'static str {
+        "find messages of the room, best match first"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let query = match server::SearchQuery::parse(args.rest().unwrap_or_default()) {
+            Ok(query) if !query.is_empty() => query,
+            Ok(_) => {
+                session.error("query is required", ctx);
+                return;
+            }
+            Err(err) => {
+                session.error(&err, ctx);
+                return;
+            }
+        };
+
+        session
+            .server()
+            .send(server::Search {
+                room: session.room().to_owned(),
+                query,
+            })
+            .into_actor(session)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Some(hits)) => {
+                        for hit in &hits {
+                            act.message(&hit.message, ctx);
+                        }
+                        let results = if hits.len() == 1 { "result" } else { "results" };
+                        act.notice(&format!("{} {results}", hits.len()), ctx);
+                    }
+                    Ok(None) => act.error("room does not exist", ctx),
+                    _ => log::error!("/search: chat server is gone"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+}
+
+/// Send one page of the room history to peer and fetch the next one.
+/// `complete` is known after the first page, it tells whether the history
+/// reaches back to the requested `seq`.
+///
+/// `.wait(ctx)` holds back new room messages until the replay is done, so
+/// they arrive after the caught up marker.
+fn sync_page<S>(
+    session: &mut S,
+    room: String,
+    since: u64,
+    complete: Option<bool>,
+    ctx: &mut S::Context,
+) where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    session
+        .server()
+        .send(server::GetHistory {
+            room: room.clone(),
+            since,
+            limit: SYNC_PAGE,
+        })
+        .into_actor(session)
+        .then(move |res, act, ctx| {
+            match res {
+                Ok(Some(page)) => {
+                    let complete = complete.unwrap_or(page.complete);
+                    let last = page.messages.last().map_or(since, |stored| stored.meta.seq);
+                    for stored in &page.messages {
+                        act.message(stored, ctx);
+                    }
+
+                    if page.more {
+                        sync_page(act, room, last, Some(complete), ctx);
+                    } else {
+                        act.caught_up(page.seq, complete, ctx);
+                    }
+                }
+                Ok(None) => act.error("room does not exist", ctx),
+                _ => log::error!("/sync: chat server is gone"),
+            }
+            fut::ready(())
+        })
+        .wait(ctx);
+}
+
+/// Parse the message id argument
+fn message_id<S: ChatSession>(
+    arg: Option<&str>,
+    session: &mut S,
+    ctx: &mut S::Context,
+) -> Option<u64> {
+    match arg.map(str::parse::<u64>) {
+        Some(Ok(id)) => Some(id),
+        Some(Err(_)) => {
+            session.error("message id must be a number", ctx);
+            None
+        }
+        None => {
+            session.error("message id is required", ctx);
+            None
+        }
+    }
+}
+
+/// Send edit, delete or reaction of `command` to chat server, only
+/// failures are answered. The room tells everybody about the change.
+fn change<S, M>(command: &'static str, msg: M, session: &mut S, ctx: &mut S::Context)
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+    M: actix::Message<Result = Result<(), String>> + Send + 'static,
+    server::ChatServer: Handler<M>,
+{
+    session
+        .server()
+        .send(msg)
+        .into_actor(session)
+        .then(move |res, act, ctx| {
+            match res {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => act.error(&err, ctx),
+                _ => log::error!("{command}: chat server is gone"),
+            }
+            fut::ready(())
+        })
+        .wait(ctx)
+}
+
+/// `/edit id text`
+pub struct Edit;
+
+impl<S> Command<S> for Edit
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "edit"
+    }
+
+    fn usage(&self) -> &'static str {
+        "id text"
+    }
+
+    fn help(&self) -> &'static str {
+        "change the text of your message"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let rest = args.rest().unwrap_or_default();
+        let (id, text) = rest.split_once(' ').unwrap_or((rest, ""));
+        let id = Some(id).filter(|id| !id.is_empty());
+        let Some(message_id) = message_id(id, session, ctx) else {
+            return;
+        };
+        if text.trim().is_empty() {
+            session.error("text is required", ctx);
+            return;
+        }
+
+        let text = match session.name() {
+            Some(name) => format!("{name}: {}", text.trim()),
+            None => text.trim().to_owned(),
+        };
+        let msg = server::Edit {
+            id: session.id(),
+            message_id,
+            text,
+        };
+        change("/edit", msg, session, ctx);
+    }
+}
+
+/// `/delete id`
+pub struct Delete;
+
+impl<S> Command<S> for Delete
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "delete"
+    }
+
+    fn usage(&self) -> &'static str {
+        "id"
+    }
+
+    fn help(&self) -> &'static str {
+        "delete your message, moderators can delete any message"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let Some(message_id) = message_id(args.iter().next(), session, ctx) else {
+            return;
+        };
+
+        let msg = server::Delete {
+            id: session.id(),
+            message_id,
+        };
+        change("/delete", msg, session, ctx);
+    }
+}
+
+/// Add or remove a reaction, `args` are the message id and the emoji
+fn react<S>(args: Args<'_>, add: bool, session: &mut S, ctx: &mut S::Context)
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    let mut args = args.iter();
+    let Some(message_id) = message_id(args.next(), session, ctx) else {
+        return;
+    };
+    let emoji = match args.next() {
+        Some(emoji) if emoji.len() <= MAX_REACTION_LEN => emoji,
+        Some(_) => {
+            session.error("reaction is too long", ctx);
+            return;
+        }
+        None => {
+            session.error("emoji is required", ctx);
+            return;
+        }
+    };
+
+    let msg = server::React {
+        id: session.id(),
+        message_id,
+        emoji: emoji.to_owned(),
+        add,
+    };
+    change(if add { "/react" } else { "/unreact" }, msg, session, ctx);
+}
+
+/// `/react id emoji`
+pub struct React;
+
+impl<S> Command<S> for React
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "react"
+    }
+
+    fn usage(&self) -> &'static str {
+        "id emoji"
+    }
+
+    fn help(&self) -> &'static str {
+        "react to a message"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        react(args, true, session, ctx);
+    }
+}
+
+/// `/unreact id emoji`
+pub struct Unreact;
+
+impl<S> Command<S> for Unreact
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "unreact"
+    }
+
+    fn usage(&self) -> &'static str {
+        "id emoji"
+    }
+
+    fn help(&self) -> &'static str {
+        "remove your reaction"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        react(args, false, session, ctx);
+    }
+}
+
+/// `/reply id text`
+pub struct Reply;
+
+impl<S> Command<S> for Reply
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "reply"
+    }
+
+    fn usage(&self) -> &'static str {
+        "id text"
+    }
+
+    fn help(&self) -> &'static str {
+        "reply to a message in its thread"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let rest = args.rest().unwrap_or_default();
+        let (id, text) = rest.split_once(' ').unwrap_or((rest, ""));
+        let id = Some(id).filter(|id| !id.is_empty());
+        let Some(parent) = message_id(id, session, ctx) else {
+            return;
+        };
+        if text.trim().is_empty() {
+            session.error("text is required", ctx);
+            return;
+        }
+
+        let text = match session.name() {
+            Some(name) => format!("{name}: {}", text.trim()),
+            None => text.trim().to_owned(),
+        };
+        let msg = server::ThreadReply {
+            id: session.id(),
+            parent,
+            msg: text.into(),
+        };
+        change("/reply", msg, session, ctx);
+    }
+}
+
+/// `/thread id`, send the thread of a message to peer
+pub struct Thread;
+
+impl<S> Command<S> for Thread
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "thread"
+    }
+
+    fn usage(&self) -> &'static str {
+        "id"
+    }
+
+    fn help(&self) -> &'static str {
+        "get the messages of the thread of a message"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let Some(message_id) = message_id(args.iter().next(), session, ctx) else {
+            return;
+        };
+
+        session
+            .server()
+            .send(server::GetThread {
+                id: session.id(),
+                message_id,
+            })
+            .into_actor(session)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(messages)) => {
+                        for stored in &messages {
+                            act.message(stored, ctx);
+                        }
+                    }
+                    Ok(Err(err)) => act.error(&err, ctx),
+                    _ => log::error!("/thread: chat server is gone"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+}
+
+/// `/follow id`
+pub struct Follow;
+
+impl<S> Command<S> for Follow
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "follow"
+    }
+
+    fn usage(&self) -> &'static str {
+        "id"
+    }
+
+    fn help(&self) -> &'static str {
+        "get notified about replies in the thread of a message"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let Some(message_id) = message_id(args.iter().next(), session, ctx) else {
+            return;
+        };
+
+        let msg = server::Follow {
+            id: session.id(),
+            message_id,
+            follow: true,
+        };
+        change("/follow", msg, session, ctx);
+    }
+}
+
+/// `/unfollow id`
+pub struct Unfollow;
+
+impl<S> Command<S> for Unfollow
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "unfollow"
+    }
+
+    fn usage(&self) -> &'static str {
+        "id"
+    }
+
+    fn help(&self) -> &'static str {
+        "stop the notifications of a thread"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let Some(message_id) = message_id(args.iter().next(), session, ctx) else {
+            return;
+        };
+
+        let msg = server::Follow {
+            id: session.id(),
+            message_id,
+            follow: false,
+        };
+        change("/unfollow", msg, session, ctx);
+    }
+}
+
+/// `/mod token`
+pub struct Moderate;
+
+impl<S> Command<S> for Moderate
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "mod"
+    }
+
+    fn usage(&self) -> &'static str {
+        "token"
+    }
+
+    fn help(&self) -> &'static str {
+        "become moderator of the room"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let Some(token) = args.rest() else {
+            session.error("token is required", ctx);
+            return;
+        };
+
+        session
+            .server()
+            .send(server::Moderate {
+                id: session.id(),
+                token: token.to_owned(),
+            })
+            .into_actor(session)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.notice("you are moderator of the room", ctx),
+                    Ok(Err(err)) => act.error(&err, ctx),
+                    _ => log::error!("/mod: chat server is gone"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+}
+
+/// `/token`, token of the user for attachment uploads and downloads and to
+/// resume after a reconnect
+pub struct Token;
+
+impl<S> Command<S> for Token
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "token"
+    }
+
+    fn help(&self) -> &'static str {
+        "get the token to share files in your room and to resume later"
+    }
+
+    fn run(&self, _: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        session
+            .server()
+            .send(server::GetToken { id: session.id() })
+            .into_actor(session)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(token) => act.notice(&format!("token {token}"), ctx),
+                    _ => log::error!("/token: chat server is gone"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+}
+
+/// `/resume token`, continue as the user of an earlier session, your
+/// messages stay yours after a reconnect
+pub struct Resume;
+
+impl<S> Command<S> for Resume
+where
+    S: ChatSession,
+    S::Context: AsyncContext<S>,
+{
+    fn name(&self) -> &'static str {
+        "resume"
+    }
+
+    fn usage(&self) -> &'static str {
+        "token"
+    }
+
+    fn help(&self) -> &'static str {
+        "continue as the user of the token from /token"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        let Some(token) = args.rest() else {
+            session.error("token is required", ctx);
+            return;
+        };
+
+        session
+            .server()
+            .send(server::Resume {
+                id: session.id(),
+                token: token.to_owned(),
+            })
+            .into_actor(session)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(Ok(())) => act.notice("resumed", ctx),
+                    Ok(Err(err)) => act.error(&err, ctx),
+                    _ => log::error!("/resume: chat server is gone"),
+                }
+                fut::ready(())
+            })
+            .wait(ctx)
+    }
+}
+
+/// `/away [reason]`
+pub struct Away;
+
+impl<S: ChatSession> Command<S> for Away {
+    fn name(&self) -> &'static str {
+        "away"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[reason]"
+    }
+
+    fn help(&self) -> &'static str {
+        "tell the room you are away"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, _: &mut S::Context) {
+        session.server().do_send(server::Away {
+            id: session.id(),
+            reason: args.rest().map(str::to_owned),
+        });
+    }
+}
+
+/// `/back`
+pub struct Back;
+
+impl<S: ChatSession> Command<S> for Back {
+    fn name(&self) -> &'static str {
+        "back"
+    }
+
+    fn help(&self) -> &'static str {
+        "you are no longer away"
+    }
+
+    fn run(&self, _: Args<'_>, session: &mut S, _: &mut S::Context) {
+        session.server().do_send(server::Back { id: session.id() });
+    }
+}
+
+/// `/ping [token]`, browsers can not send websocket pings, they time this
+/// reply instead
+pub struct Ping;
+
+impl<S: ChatSession> Command<S> for Ping {
+    fn name(&self) -> &'static str {
+        "ping"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[token]"
+    }
+
+    fn help(&self) -> &'static str {
+        "reply with pong and the token"
+    }
+
+    fn run(&self, args: Args<'_>, session: &mut S, ctx: &mut S::Context) {
+        match args.rest() {
+            Some(token) => session.notice(&format!("pong {token}"), ctx),
+            None => session.notice("pong", ctx),
+        }
+    }
+}
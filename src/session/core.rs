@@ -0,0 +1,185 @@
+//! `Session` is the part every chat session has in common: registration
+//! with `ChatServer`, heartbeat and disconnect. Session kinds only keep a
+//! `SessionCore` and implement what is specific to their transport.
+
+use std::time::{Duration, Instant};
+
+use actix::{dev::ToEnvelope, prelude::*};
+use actix_web_actors::ws;
+
+use super::{CLIENT_TIMEOUT, HEARTBEAT_INTERVAL};
+use crate::server;
+
+/// State of the connection with the chat server
+#[derive(Debug)]
+pub struct SessionCore {
+    /// unique session id
+    pub id: usize,
+
+    /// Client must answer ping at least once per 10 seconds (CLIENT_TIMEOUT),
+    /// otherwise we drop connection.
+    pub hb: Instant,
+
+    /// Round-trip time measured by the last heartbeat
+    pub rtt: Option<Duration>,
+
+    /// Chat server
+    pub addr: Addr<server::ChatServer>,
+
+    /// ping timestamps are relative to this
+    epoch: Instant,
+}
+
+impl SessionCore {
+    pub fn new(addr: Addr<server::ChatServer>) -> SessionCore {
+        SessionCore {
+            id: 0,
+            hb: Instant::now(),
+            rtt: None,
+            addr,
+            epoch: Instant::now(),
+        }
+    }
+
+    /// Microseconds since the session started, sent with heartbeat pings
+    pub fn timestamp(&self) -> u64 {
+        self.epoch.elapsed().as_micros() as u64
+    }
+
+    /// Peer answered the ping sent at `timestamp`, returns the round-trip
+    /// time. Pongs without a valid timestamp only count as heartbeat.
+    pub fn pong(&mut self, timestamp: Option<u64>) -> Option<Duration> {
+        self.hb = Instant::now();
+
+        let sent = Duration::from_micros(timestamp?);
+        let rtt = self.epoch.elapsed().checked_sub(sent)?;
+        self.rtt = Some(rtt);
+        Some(rtt)
+    }
+}
+
+pub trait Session: Actor + Sized {
+    fn core(&self) -> &SessionCore;
+
+    fn core_mut(&mut self) -> &mut SessionCore;
+
+    /// Session id given by the chat server
+    fn id(&self) -> usize {
+        self.core().id
+    }
+
+    /// Chat server
+    fn server(&self) -> &Addr<server::ChatServer> {
+        &self.core().addr
+    }
+
+    /// Send heartbeat ping to the peer, sessions without `heartbeat` do not
+    /// need it
+    fn ping(&mut self, _: &mut Self::Context) {}
+
+    /// helper method that sends ping to client every 5 seconds (HEARTBEAT_INTERVAL).
+    ///
+    /// also this method checks heartbeats from client
+    fn heartbeat(&self, ctx: &mut Self::Context)
+    where
+        Self::Context: AsyncContext<Self>,
+    {
+        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
+            // check client heartbeats
+            if Instant::now().duration_since(act.core().hb) > CLIENT_TIMEOUT {
+                // heartbeat timed out
+                log::info!("Client heartbeat failed, disconnecting!");
+
+                // stop actor, `stopping` notifies chat server
+                ctx.stop();
+
+                // don't try to send a ping
+                return;
+            }
+
+            act.ping(ctx);
+        });
+    }
+
+    /// Peer answered a heartbeat ping, chat server learns the round-trip
+    /// time
+    fn pong(&mut self, timestamp: Option<u64>) {
+        if let Some(rtt) = self.core_mut().pong(timestamp) {
+            self.server().do_send(server::Rtt { id: self.id(), rtt });
+        }
+    }
+
+    /// Register session with ChatServer.
+    ///
+    /// `AsyncContext::wait` register future within context, but context
+    /// waits until this future resolves before processing any other events.
+    fn register(&self, ctx: &mut Self::Context)
+    where
+        Self: Handler<server::Message>,
+        Self::Context: AsyncContext<Self> + ToEnvelope<Self, server::Message>,
+    {
+        let addr = ctx.address();
+        self.server()
+            .send(server::Connect {
+                addr: addr.recipient(),
+            })
+            .into_actor(self)
+            .then(|res, act, ctx| {
+                match res {
+                    Ok(res) => act.core_mut().id = res,
+                    // something is wrong with chat server
+                    _ => ctx.stop(),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    /// notify chat server
+    fn unregister(&self) {
+        self.server().do_send(server::Disconnect { id: self.id() });
+    }
+
+    /// Handle websocket errors and control frames, text and binary frames
+    /// are returned to the session
+    fn handle_ws(
+        &mut self,
+        msg: Result<ws::Message, ws::ProtocolError>,
+        ctx: &mut ws::WebsocketContext<Self>,
+    ) -> Option<ws::Message>
+    where
+        Self: Actor<Context = ws::WebsocketContext<Self>>,
+    {
+        let msg = match msg {
+            Err(_) => {
+                ctx.stop();
+                return None;
+            }
+            Ok(msg) => msg,
+        };
+
+        log::debug!("WEBSOCKET MESSAGE: {msg:?}");
+        match msg {
+            ws::Message::Ping(msg) => {
+                self.core_mut().hb = Instant::now();
+                ctx.pong(&msg);
+            }
+            ws::Message::Pong(payload) => {
+                // payload is the timestamp of the ping
+                let timestamp = payload.as_ref().try_into().ok().map(u64::from_be_bytes);
+                self.pong(timestamp);
+            }
+            ws::Message::Close(reason) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            ws::Message::Continuation(_) => {
+                ctx.stop();
+            }
+            ws::Message::Nop => (),
+            msg => return Some(msg),
+        }
+
+        None
+    }
+}
@@ -1,58 +1,118 @@
-use std::time::Instant;
+use std::sync::OnceLock;
 use actix::prelude::*;
 use actix_web_actors::ws;
+use rand::Rng;
 
 use crate::server;
 
-use super::{HEARTBEAT_INTERVAL, CLIENT_TIMEOUT};
+use super::{
+    release_when_written, Args, ChatSession, Command, Registry, Session, SessionCore,
+};
 
 
 #[derive(Debug)]
 pub struct WsGameSession {
-    /// unique session id
-    pub id: usize,
-
-    pub ping_time: Instant,
+    /// Connection with the chat server
+    pub core: SessionCore,
 
     /// joined room
     pub room_name: String,
 
     /// peer name
     pub cli_name: Option<String>,
+}
+
+impl Session for WsGameSession {
+    fn core(&self) -> &SessionCore {
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut SessionCore {
+        &mut self.core
+    }
+
+    fn ping(&mut self, ctx: &mut Self::Context) {
+        ctx.ping(&self.core.timestamp().to_be_bytes());
+    }
+}
+
+impl ChatSession for WsGameSession {
+    fn room(&self) -> &str {
+        &self.room_name
+    }
 
-    /// Chat server
-    pub srv_addr: Addr<server::ChatServer>,
+    fn set_room(&mut self, room: &str) {
+        self.room_name = room.to_owned();
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.cli_name.as_deref()
+    }
+
+    fn set_name(&mut self, name: &str) {
+        self.cli_name = Some(name.to_owned());
+    }
+
+    fn notice(&mut self, text: &str, ctx: &mut Self::Context) {
+        ctx.text(text);
+    }
+
+    fn error(&mut self, text: &str, ctx: &mut Self::Context) {
+        ctx.text(format!("!!! {text}"));
+    }
+
+    fn commands() -> &'static Registry<Self> {
+        static COMMANDS: OnceLock<Registry<WsGameSession>> = OnceLock::new();
+        COMMANDS.get_or_init(|| Registry::new().with(Roll))
+    }
 }
 
-impl WsGameSession {
-    /// helper method that sends ping to client every 5 seconds (HEARTBEAT_INTERVAL).
-    ///
-    /// also this method checks heartbeats from client
-    fn ping_time(&self, ctx: &mut ws::WebsocketContext<Self>) {
-         // 5秒毎に永続稼動
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            // check client heartbeats
-            if Instant::now().duration_since(act.ping_time) > CLIENT_TIMEOUT {
-                // heartbeat timed out
-                println!("Websocket Client heartbeat failed, disconnecting!");
-
-                // notify chat server
-                act.srv_addr.do_send(server::Disconnect { id: act.id });
-
-                // stop actor
-                ctx.stop();
-
-                // don't try to send a ping
+/// `/roll [sides]`, roll a die in front of the room
+struct Roll;
+
+impl Command<WsGameSession> for Roll {
+    fn name(&self) -> &'static str {
+        "roll"
+    }
+
+    fn usage(&self) -> &'static str {
+        "[sides]"
+    }
+
+    fn help(&self) -> &'static str {
+        "roll a die, it has 6 sides by default"
+    }
+
+    fn run(
+        &self,
+        args: Args<'_>,
+        session: &mut WsGameSession,
+        ctx: &mut ws::WebsocketContext<WsGameSession>,
+    ) {
+        let sides = match args.iter().next().map(str::parse::<u32>) {
+            None => 6,
+            Some(Ok(sides)) if sides > 1 => sides,
+            _ => {
+                session.error("sides must be a number larger than 1", ctx);
                 return;
             }
+        };
 
-            ctx.ping(b"");
+        let roll = rand::thread_rng().gen_range(1..=sides);
+        let text = format!(
+            "{} rolled {roll} (1-{sides})",
+            session.name().unwrap_or("someone")
+        );
+
+        session.notice(&text, ctx);
+        session.server().do_send(server::ClientMessage {
+            id: session.id(),
+            msg: text.into(),
+            room: session.room().to_owned(),
         });
     }
 }
 
-
-
 impl Actor for WsGameSession {
     type Context = ws::WebsocketContext<Self>;
 
@@ -60,33 +120,14 @@ impl Actor for WsGameSession {
     /// We register ws session with ChatServer
     fn started(&mut self, ctx: &mut Self::Context) {
         // we'll start heartbeat process on session start.
-        self.ping_time(ctx);
-
-        // register self in chat server. `AsyncContext::wait` register
-        // future within context, but context waits until this future resolves
-        // before processing any other events.
-        // HttpContext::state() is instance of WsChatSessionState, state is shared
-        // across all routes within application
-        let addr = ctx.address();
-        self.srv_addr
-            .send(server::Connect {
-                addr: addr.recipient(),
-            })
-            .into_actor(self)
-            .then(|res, act, ctx| {
-                match res {
-                    Ok(res) => act.id = res,
-                    // something is wrong with chat server
-                    _ => ctx.stop(),
-                }
-                fut::ready(())
-            })
-            .wait(ctx);
+        self.heartbeat(ctx);
+
+        // register self in chat server
+        self.register(ctx);
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        // notify chat server
-        self.srv_addr.do_send(server::Disconnect { id: self.id });
+        self.unregister();
         Running::Stop
     }
 }
@@ -96,104 +137,57 @@ impl Handler<server::Message> for WsGameSession {
     type Result = ();
 
     fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        let overflowed = msg.overflowed();
+        release_when_written(msg.receipt, ctx);
+        match msg.payload {
+            server::Payload::Text(text) => ctx.text(text),
+            server::Payload::Binary(data) => ctx.binary(data),
+            // room events are only shown by chat sessions
+            server::Payload::Event(_) => (),
+        }
+
+        if overflowed {
+            // peer can not keep up with the room
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("too slow".to_owned()),
+            }));
+            ctx.stop();
+        }
     }
 }
 
 /// WebSocket message handler
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsGameSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        let msg = match msg {
-            Err(_) => {
-                ctx.stop();
-                return;
-            }
-            Ok(msg) => msg,
+        let Some(msg) = self.handle_ws(msg, ctx) else {
+            return;
         };
 
-        log::debug!("WEBSOCKET MESSAGE: {msg:?}");
         match msg {
-            ws::Message::Ping(msg) => {
-                self.ping_time = Instant::now();
-                ctx.pong(&msg);
-            }
-            ws::Message::Pong(_) => {
-                self.ping_time = Instant::now();
-            }
             ws::Message::Text(text) => {
                 let m = text.trim();
                 // we check for /sss type of messages
                 if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            self.srv_addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room_name = v[1].to_owned();
-                                self.srv_addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room_name.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.cli_name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {m:?}")),
-                    }
+                    Self::commands().run(m, self, ctx);
                 } else {
                     let msg = if let Some(ref name) = self.cli_name {
-                        format!("{name}: {m}")
+                        format!("{name}: {m}").into()
                     } else {
-                        m.to_owned()
+                        // reuse the frame buffer, no copy
+                        text.slice_ref(m)
                     };
                     // send message to chat server
-                    self.srv_addr.do_send(server::ClientMessage {
-                        id: self.id,
+                    self.server().do_send(server::ClientMessage {
+                        id: self.id(),
                         msg,
                         room: self.room_name.clone(),
                     })
                 }
             }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
-            ws::Message::Close(reason) => {
-                ctx.close(reason);
-                ctx.stop();
-            }
-            ws::Message::Continuation(_) => {
-                ctx.stop();
-            }
-            ws::Message::Nop => (),
+            ws::Message::Binary(_) => log::debug!("binary frame ignored"),
+            // control frames are handled by `handle_ws`
+            _ => (),
         }
     }
 }
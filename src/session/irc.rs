@@ -0,0 +1,356 @@
+//! Minimal IRC server. IRC clients share the rooms of `ChatServer` with
+//! websocket users, every room is a `#channel`. A client is in one channel
+//! at a time, joining another channel parts the current one.
+
+use std::{borrow::Cow, io, time::Instant};
+
+use actix::prelude::*;
+use actix_codec::LinesCodec;
+use bytestring::ByteString;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::FramedRead;
+
+use super::{
+    lines::{one_line, LineWriter},
+    Session, SessionCore,
+};
+use crate::server;
+
+/// Name of the server in replies
+const SERVER_NAME: &str = "chat";
+
+/// Longest line a client may send, IRC allows 512 bytes
+const MAX_LINE_LENGTH: usize = 512;
+
+/// Accept IRC connections
+pub async fn serve(addr: &str, srv: Addr<server::ChatServer>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("starting IRC listener at {addr}");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        log::debug!("irc client connected from {peer}");
+
+        IrcSession::start_with(stream, srv.clone());
+    }
+}
+
+/// Split line into command and parameters, the trailing parameter starts
+/// with `:` and may contain spaces
+fn parse(line: &str) -> Option<(String, Vec<&str>)> {
+    let mut line = line.trim_end();
+
+    // prefix of the client is ignored
+    if line.starts_with(':') {
+        line = line.split_once(' ')?.1;
+    }
+
+    let (line, trailing) = match line.split_once(" :") {
+        Some((line, trailing)) => (line, Some(trailing)),
+        None => (line, None),
+    };
+
+    let mut parts = line.split_whitespace();
+    let command = parts.next()?.to_ascii_uppercase();
+    let mut params: Vec<&str> = parts.collect();
+    params.extend(trailing);
+
+    Some((command, params))
+}
+
+/// Nick or channel name, they end at the first space
+fn token(name: &str) -> Cow<'_, str> {
+    if name.contains(|c: char| c.is_whitespace() || c == '\0') {
+        Cow::Owned(name.replace(|c: char| c.is_whitespace() || c == '\0', "_"))
+    } else {
+        Cow::Borrowed(name)
+    }
+}
+
+pub struct IrcSession {
+    /// Connection with the chat server
+    pub core: SessionCore,
+
+    /// joined room, without `#`
+    pub room: Option<String>,
+
+    /// nick name
+    pub name: Option<String>,
+
+    /// Write half of the connection
+    writer: LineWriter,
+}
+
+impl IrcSession {
+    fn start_with(stream: TcpStream, addr: Addr<server::ChatServer>) -> Addr<Self> {
+        let (read, write) = stream.into_split();
+
+        IrcSession::create(move |ctx| {
+            ctx.add_stream(FramedRead::new(
+                read,
+                LinesCodec::new_with_max_length(MAX_LINE_LENGTH),
+            ));
+
+            IrcSession {
+                core: SessionCore::new(addr),
+                room: None,
+                name: None,
+                writer: LineWriter::new(write),
+            }
+        })
+    }
+
+    fn nick(&self) -> &str {
+        self.name.as_deref().unwrap_or("*")
+    }
+
+    fn send(&mut self, line: impl Into<ByteString>) {
+        self.writer.write(line);
+    }
+
+    /// Numeric reply to the client
+    fn reply(&mut self, code: &str, params: &str) {
+        let line = format!(":{SERVER_NAME} {code} {} {params}", self.nick());
+        self.send(line);
+    }
+
+    /// Channel joined, tell the client who is there
+    fn joined(&mut self, room: String, ctx: &mut Context<Self>) {
+        let nick = self.nick().to_owned();
+        self.send(format!(":{nick}!{nick}@{SERVER_NAME} JOIN #{room}"));
+        self.room = Some(room.clone());
+        self.names(room, ctx);
+    }
+
+    fn parted(&mut self) {
+        if let Some(room) = self.room.take() {
+            let nick = self.nick().to_owned();
+            self.send(format!(":{nick}!{nick}@{SERVER_NAME} PART #{room}"));
+        }
+    }
+
+    fn names(&mut self, room: String, ctx: &mut Context<Self>) {
+        self.server()
+            .send(server::Members { room: room.clone() })
+            .into_actor(self)
+            .then(move |res, act, _| {
+                let room = token(&room);
+                if let Ok(members) = res {
+                    let names: Vec<_> = members.iter().map(|member| token(&member.name)).collect();
+                    act.reply("353", &format!("= #{room} :{}", names.join(" ")));
+                }
+                act.reply("366", &format!("#{room} :End of /NAMES list"));
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+
+    fn command(&mut self, command: &str, params: &[&str], ctx: &mut Context<Self>) {
+        match command {
+            "NICK" => match params.first().filter(|nick| !nick.is_empty()) {
+                Some(nick) => {
+                    let first = self.name.is_none();
+                    self.name = Some((*nick).to_owned());
+                    self.server().do_send(server::SetName {
+                        id: self.id(),
+                        name: (*nick).to_owned(),
+                    });
+
+                    if first {
+                        self.reply("001", ":Welcome to the chat");
+                        // every session starts in the main room
+                        self.joined("main".to_owned(), ctx);
+                    }
+                }
+                None => self.reply("431", ":No nickname given"),
+            },
+            "USER" | "CAP" | "MODE" => (),
+            "PING" => {
+                let token = params.first().copied().unwrap_or(SERVER_NAME);
+                self.send(format!(":{SERVER_NAME} PONG {SERVER_NAME} :{token}"));
+            }
+            "PONG" => self.pong(params.last().and_then(|token| token.parse().ok())),
+            "JOIN" => match params.first() {
+                Some(channels) => {
+                    // one room at a time, the last channel wins
+                    let room = channels.rsplit(',').next().unwrap_or_default();
+                    let room = room.trim_start_matches('#').to_owned();
+                    if room.is_empty() {
+                        self.reply("403", &format!("{channels} :No such channel"));
+                        return;
+                    }
+
+                    self.parted();
+                    self.server().do_send(server::Join {
+                        id: self.id(),
+                        name: room.clone(),
+                    });
+                    self.joined(room, ctx);
+                }
+                None => self.reply("461", "JOIN :Not enough parameters"),
+            },
+            "PART" => {
+                self.parted();
+                self.server().do_send(server::Leave { id: self.id() });
+            }
+            "PRIVMSG" => match (params.first(), params.get(1)) {
+                (Some(target), Some(text)) => {
+                    let room = target.trim_start_matches('#');
+                    if self.room.as_deref() != Some(room) {
+                        self.reply("442", &format!("{target} :You're not on that channel"));
+                        return;
+                    }
+
+                    // same format as websocket clients
+                    let msg = format!("{}: {text}", self.nick());
+                    self.server().do_send(server::ClientMessage {
+                        id: self.id(),
+                        msg: msg.into(),
+                        room: room.to_owned(),
+                    });
+                }
+                _ => self.reply("412", ":No text to send"),
+            },
+            "LIST" => {
+                self.server()
+                    .send(server::ListRooms)
+                    .into_actor(self)
+                    .then(|res, act, _| {
+                        act.reply("321", "Channel :Users Name");
+                        if let Ok(rooms) = res {
+                            for room in rooms {
+                                act.reply("322", &format!("#{} 0 :", token(&room)));
+                            }
+                        }
+                        act.reply("323", ":End of /LIST");
+                        fut::ready(())
+                    })
+                    .wait(ctx);
+            }
+            "NAMES" => {
+                let room = params
+                    .first()
+                    .map(|room| room.trim_start_matches('#').to_owned())
+                    .or_else(|| self.room.clone());
+
+                if let Some(room) = room {
+                    self.names(room, ctx);
+                }
+            }
+            "QUIT" => ctx.stop(),
+            _ => self.reply("421", &format!("{command} :Unknown command")),
+        }
+    }
+}
+
+impl Session for IrcSession {
+    fn core(&self) -> &SessionCore {
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut SessionCore {
+        &mut self.core
+    }
+
+    fn ping(&mut self, _: &mut Self::Context) {
+        let timestamp = self.core.timestamp();
+        self.send(format!("PING :{timestamp}"));
+    }
+}
+
+impl Actor for IrcSession {
+    type Context = Context<Self>;
+
+    /// Method is called on actor start.
+    /// We register irc session with ChatServer
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.heartbeat(ctx);
+
+        self.register(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.unregister();
+        Running::Stop
+    }
+}
+
+/// Handle messages from chat server.
+///
+/// Peer messages are sent as `PRIVMSG` from their author, status messages
+/// as `NOTICE`.
+impl Handler<server::Message> for IrcSession {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) -> Self::Result {
+        if msg.overflowed() {
+            // peer can not keep up with the room
+            self.writer.close();
+            ctx.stop();
+            return Box::pin(fut::ready(()));
+        }
+
+        let send = match (&self.room, &msg.payload) {
+            (Some(room), server::Payload::Text(text)) => {
+                let room = token(room);
+                let line = match &msg.meta {
+                    Some(meta) => {
+                        // the text carries the name prefix of named sessions
+                        let from = token(&meta.author);
+                        let text = text
+                            .strip_prefix(&*meta.author)
+                            .and_then(|text| text.strip_prefix(": "))
+                            .unwrap_or(text);
+                        format!(
+                            ":{from}!{from}@{SERVER_NAME} PRIVMSG #{room} :{}",
+                            one_line(text)
+                        )
+                    }
+                    None => format!(":{SERVER_NAME} NOTICE #{room} :{}", one_line(text)),
+                };
+                Some(self.writer.send(line.into()))
+            }
+            _ => None,
+        };
+
+        // the next message is released when this one is queued for writing
+        Box::pin(
+            async move {
+                let written = match send {
+                    Some(send) => send.await.is_ok(),
+                    None => true,
+                };
+                drop(msg);
+                written
+            }
+            .into_actor(self)
+            .map(|written, _, ctx| {
+                if !written {
+                    // peer is gone
+                    ctx.stop();
+                }
+            }),
+        )
+    }
+}
+
+/// Line handler
+impl StreamHandler<Result<String, io::Error>> for IrcSession {
+    fn handle(&mut self, line: Result<String, io::Error>, ctx: &mut Self::Context) {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                log::debug!("irc client error: {err}");
+                ctx.stop();
+                return;
+            }
+        };
+
+        // any line from the client shows it is alive
+        self.core.hb = Instant::now();
+
+        if let Some((command, params)) = parse(&line) {
+            self.command(&command, &params, ctx);
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Write half of the line protocols. Lines are written by a task of their
+//! own, the bounded channel in front of it limits what waits for a peer
+//! that does not read. Line breaks in a line are written as spaces.
+
+use std::{borrow::Cow, future::Future};
+
+use bytestring::ByteString;
+use tokio::{
+    io::AsyncWriteExt,
+    net::tcp::OwnedWriteHalf,
+    sync::mpsc::{self, error::SendError},
+};
+
+/// Lines that may wait for the peer
+const LINE_BUFFER: usize = 16;
+
+/// Text for a single line, a line break in text of other users would let
+/// them forge lines
+pub fn one_line(text: &str) -> Cow<'_, str> {
+    if text.contains(['\r', '\n', '\0']) {
+        Cow::Owned(text.replace(['\r', '\n', '\0'], " "))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+pub struct LineWriter {
+    tx: Option<mpsc::Sender<ByteString>>,
+}
+
+impl LineWriter {
+    pub fn new(mut write: OwnedWriteHalf) -> LineWriter {
+        let (tx, mut rx) = mpsc::channel::<ByteString>(LINE_BUFFER);
+
+        actix::spawn(async move {
+            while let Some(line) = rx.recv().await {
+                let mut buf = Vec::with_capacity(line.len() + 1);
+                buf.extend_from_slice(one_line(&line).as_bytes());
+                buf.push(b'\n');
+
+                if let Err(err) = write.write_all(&buf).await {
+                    log::debug!("can not write to peer: {err}");
+                    return;
+                }
+            }
+            let _ = write.shutdown().await;
+        });
+
+        LineWriter { tx: Some(tx) }
+    }
+
+    /// Queue a reply, it is dropped when the peer does not read
+    pub fn write(&self, line: impl Into<ByteString>) {
+        if let Some(tx) = &self.tx {
+            if tx.try_send(line.into()).is_err() {
+                log::debug!("peer does not read, line dropped");
+            }
+        }
+    }
+
+    /// Wait until there is room for the line, fails when the connection is
+    /// closed
+    pub fn send(
+        &self,
+        line: ByteString,
+    ) -> impl Future<Output = Result<(), SendError<ByteString>>> + 'static {
+        let tx = self.tx.clone();
+        async move {
+            match tx {
+                Some(tx) => tx.send(line).await,
+                None => Err(SendError(line)),
+            }
+        }
+    }
+
+    /// Queued lines are written, then the connection is closed
+    pub fn close(&mut self) {
+        self.tx = None;
+    }
+}
@@ -1,11 +1,37 @@
-use std::time::{Duration, Instant};
+use std::{
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::OnceLock,
+    task::{self, Poll},
+    time::Duration,
+};
 
 use actix::prelude::*;
 use actix_web_actors::ws;
+use bytes::Bytes;
+use bytestring::ByteString;
 
 use crate::server;
 
+mod codec;
+mod command;
+mod core;
 mod game;
+pub mod irc;
+mod lines;
+pub mod poll;
+mod proto;
+pub mod sse;
+pub mod tcp;
+
+use self::codec::{Reply, Request};
+pub use self::{
+    codec::Codec,
+    command::{Args, ChatSession, Command, Registry},
+    core::{Session, SessionCore},
+    game::WsGameSession,
+};
 
 /// How often heartbeat pings are sent
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
@@ -13,14 +39,64 @@ const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 /// How long before lack of client response causes a timeout
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 
+/// Keep the receipt of a message until the websocket context runs again.
+/// The HTTP connection only runs the context while its write buffer has
+/// room, so a peer that does not read holds back its outbox instead of
+/// growing the write buffer.
+fn release_when_written<A>(receipt: server::Receipt, ctx: &mut ws::WebsocketContext<A>)
+where
+    A: Actor<Context = ws::WebsocketContext<A>>,
+{
+    ctx.spawn(fut::wrap_future(NextPoll(false)).map(move |_, _, _| drop(receipt)));
+}
+
+/// Pending on the first poll, ready on the next one
+struct NextPoll(bool);
+
+impl Future for NextPoll {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// What websocket sessions do with binary frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryMode {
+    /// Binary frames are discarded
+    Ignore,
+
+    /// Binary frames hold protobuf messages of `proto/chat.proto`, a text
+    /// protocol peer that sent one gets its replies as protobuf too
+    Protobuf,
+
+    /// Binary frames are relayed to the other members of the room as is
+    Relay,
+}
+
+impl FromStr for BinaryMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(BinaryMode::Ignore),
+            "protobuf" => Ok(BinaryMode::Protobuf),
+            "relay" => Ok(BinaryMode::Relay),
+            _ => Err(format!("unknown binary mode: {s:?}")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct WsChatSession {
-    /// unique session id
-    pub id: usize,
-
-    /// Client must send ping at least once per 10 seconds (CLIENT_TIMEOUT),
-    /// otherwise we drop connection.
-    pub hb: Instant,
+    /// Connection with the chat server
+    pub core: SessionCore,
 
     /// joined room
     pub room: String,
@@ -28,50 +104,192 @@ pub struct WsChatSession {
     /// peer name
     pub name: Option<String>,
 
-    /// Chat server
-    pub addr: Addr<server::ChatServer>,
+    /// What to do with binary frames
+    pub binary: BinaryMode,
+
+    /// Wire format of the peer
+    pub codec: Codec,
 }
 
-#[derive(Debug)]
-pub struct WsGameSession {
-    /// unique session id
-    pub id: usize,
+impl WsChatSession {
+    /// Send reply in the wire format of the peer
+    fn reply(&self, reply: Reply<'_>, ctx: &mut ws::WebsocketContext<Self>) {
+        match self.codec.encode(&reply) {
+            server::Payload::Text(text) => ctx.text(text),
+            server::Payload::Binary(data) => ctx.binary(data),
+            // codecs only produce frames
+            server::Payload::Event(_) => (),
+        }
+    }
 
-    pub ping_time: Instant,
+    /// Send peer message to the room
+    fn chat(&self, text: ByteString) {
+        let msg = if let Some(ref name) = self.name {
+            format!("{name}: {text}").into()
+        } else {
+            text
+        };
+        // send message to chat server
+        self.server().do_send(server::ClientMessage {
+            id: self.id(),
+            msg,
+            room: self.room.clone(),
+        })
+    }
 
-    /// joined room
-    pub room_name: String,
+    /// Request of a json or protobuf peer, same as the text commands
+    fn request(&mut self, request: Request, ctx: &mut ws::WebsocketContext<Self>) {
+        let commands = Self::commands();
+        match request {
+            Request::Message { text } => self.chat(text.trim().into()),
+            Request::List => commands.call("list", Args::new(""), self, ctx),
+            Request::Join { room } => commands.call("join", Args::new(&room), self, ctx),
+            Request::Name { name } => commands.call("name", Args::new(&name), self, ctx),
+            Request::Edit { id, text } => {
+                commands.call("edit", Args::new(&format!("{id} {text}")), self, ctx)
+            }
+            Request::Delete { id } => commands.call("delete", Args::new(&id), self, ctx),
+            Request::React { id, emoji } => {
+                commands.call("react", Args::new(&format!("{id} {emoji}")), self, ctx)
+            }
+            Request::Unreact { id, emoji } => {
+                commands.call("unreact", Args::new(&format!("{id} {emoji}")), self, ctx)
+            }
+            Request::Mod { token } => commands.call("mod", Args::new(&token), self, ctx),
+            Request::Resume { token } => commands.call("resume", Args::new(&token), self, ctx),
+            Request::Reply { id, text } => {
+                commands.call("reply", Args::new(&format!("{id} {text}")), self, ctx)
+            }
+            Request::Thread { id } => commands.call("thread", Args::new(&id), self, ctx),
+            Request::Follow { id } => commands.call("follow", Args::new(&id), self, ctx),
+            Request::Unfollow { id } => commands.call("unfollow", Args::new(&id), self, ctx),
+            Request::Search { query } => commands.call("search", Args::new(&query), self, ctx),
+            Request::Sync { since } => {
+                commands.call("sync", Args::new(&since.to_string()), self, ctx)
+            }
+            Request::Typing { active } => {
+                let state = if active { "start" } else { "stop" };
+                commands.call("typing", Args::new(state), self, ctx)
+            }
+        }
+    }
 
-    /// peer name
-    pub cli_name: Option<String>,
+    /// Protobuf frame from the peer
+    fn proto_frame(&mut self, data: Bytes, ctx: &mut ws::WebsocketContext<Self>) {
+        match Request::from_proto(data) {
+            Ok(Some(request)) => self.request(request, ctx),
+            Ok(None) => self.error("unknown command", ctx),
+            Err(err) => {
+                log::debug!("invalid protobuf frame: {err}");
+                self.error("invalid frame", ctx);
+            }
+        }
+    }
+}
+
+impl Session for WsChatSession {
+    fn core(&self) -> &SessionCore {
+        &self.core
+    }
 
-    /// Chat server
-    pub srv_addr: Addr<server::ChatServer>,
+    fn core_mut(&mut self) -> &mut SessionCore {
+        &mut self.core
+    }
+
+    fn ping(&mut self, ctx: &mut Self::Context) {
+        ctx.ping(&self.core.timestamp().to_be_bytes());
+    }
 }
 
-impl WsChatSession {
-    /// helper method that sends ping to client every 5 seconds (HEARTBEAT_INTERVAL).
-    ///
-    /// also this method checks heartbeats from client
-    fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
-         // 5秒毎に永続稼動
-        ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
-            // check client heartbeats
-            if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                // heartbeat timed out
-                println!("Websocket Client heartbeat failed, disconnecting!");
-
-                // notify chat server
-                act.addr.do_send(server::Disconnect { id: act.id });
-
-                // stop actor
-                ctx.stop();
-
-                // don't try to send a ping
+impl ChatSession for WsChatSession {
+    fn room(&self) -> &str {
+        &self.room
+    }
+
+    fn set_room(&mut self, room: &str) {
+        self.room = room.to_owned();
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_owned());
+    }
+
+    fn notice(&mut self, text: &str, ctx: &mut Self::Context) {
+        self.reply(Reply::Notice { text }, ctx);
+    }
+
+    fn error(&mut self, text: &str, ctx: &mut Self::Context) {
+        self.reply(Reply::Error { text }, ctx);
+    }
+
+    fn message(&mut self, stored: &server::StoredMessage, ctx: &mut Self::Context) {
+        let reply = Reply::Message {
+            text: &stored.text,
+            meta: stored.meta.clone(),
+            reactions: stored.reactions(),
+        };
+        self.reply(reply, ctx);
+    }
+
+    fn caught_up(&mut self, seq: u64, complete: bool, ctx: &mut Self::Context) {
+        self.reply(Reply::CaughtUp { seq, complete }, ctx);
+    }
+
+    fn rooms(&mut self, rooms: Vec<String>, ctx: &mut Self::Context) {
+        if self.codec == Codec::Text {
+            for room in rooms {
+                ctx.text(room);
+            }
+        } else {
+            self.reply(Reply::Rooms { rooms }, ctx);
+        }
+    }
+
+    fn commands() -> &'static Registry<Self> {
+        static COMMANDS: OnceLock<Registry<WsChatSession>> = OnceLock::new();
+        COMMANDS.get_or_init(|| Registry::new().with(Typing))
+    }
+}
+
+/// `/typing start|stop`, clients repeat the start every few seconds while
+/// the user is typing
+struct Typing;
+
+impl Command<WsChatSession> for Typing {
+    fn name(&self) -> &'static str {
+        "typing"
+    }
+
+    fn usage(&self) -> &'static str {
+        "start|stop"
+    }
+
+    fn help(&self) -> &'static str {
+        "tell the room you are typing"
+    }
+
+    fn run(
+        &self,
+        args: Args<'_>,
+        session: &mut WsChatSession,
+        ctx: &mut ws::WebsocketContext<WsChatSession>,
+    ) {
+        let active = match args.rest() {
+            Some("start") => true,
+            Some("stop") => false,
+            _ => {
+                session.error("expected start or stop", ctx);
                 return;
             }
+        };
 
-            ctx.ping(b"");
+        session.server().do_send(server::Typing {
+            id: session.id(),
+            active,
         });
     }
 }
@@ -83,33 +301,14 @@ impl Actor for WsChatSession {
     /// We register ws session with ChatServer
     fn started(&mut self, ctx: &mut Self::Context) {
         // we'll start heartbeat process on session start.
-        self.hb(ctx);
-
-        // register self in chat server. `AsyncContext::wait` register
-        // future within context, but context waits until this future resolves
-        // before processing any other events.
-        // HttpContext::state() is instance of WsChatSessionState, state is shared
-        // across all routes within application
-        let addr = ctx.address();
-        self.addr
-            .send(server::Connect {
-                addr: addr.recipient(),
-            })
-            .into_actor(self)
-            .then(|res, act, ctx| {
-                match res {
-                    Ok(res) => act.id = res,
-                    // something is wrong with chat server
-                    _ => ctx.stop(),
-                }
-                fut::ready(())
-            })
-            .wait(ctx);
+        self.heartbeat(ctx);
+
+        // register self in chat server
+        self.register(ctx);
     }
 
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        // notify chat server
-        self.addr.do_send(server::Disconnect { id: self.id });
+        self.unregister();
         Running::Stop
     }
 }
@@ -119,104 +318,78 @@ impl Handler<server::Message> for WsChatSession {
     type Result = ();
 
     fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        let overflowed = msg.overflowed();
+        release_when_written(msg.receipt, ctx);
+        match msg.payload {
+            // text protocol peers get status messages as they are
+            server::Payload::Text(text) if self.codec == Codec::Text && msg.meta.is_none() => {
+                ctx.text(text)
+            }
+            server::Payload::Text(text) => match msg.meta {
+                Some(meta) => {
+                    let reply = Reply::Message {
+                        text: &text,
+                        meta,
+                        reactions: Vec::new(),
+                    };
+                    self.reply(reply, ctx)
+                }
+                None => self.reply(Reply::Notice { text: &text }, ctx),
+            },
+            server::Payload::Binary(data) => ctx.binary(data),
+            server::Payload::Event(event) => self.reply(Reply::from(&event), ctx),
+        }
+
+        if overflowed {
+            // peer can not keep up with the room
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some("too slow".to_owned()),
+            }));
+            ctx.stop();
+        }
     }
 }
 
 /// WebSocket message handler
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
-        let msg = match msg {
-            Err(_) => {
-                ctx.stop();
-                return;
-            }
-            Ok(msg) => msg,
+        let Some(msg) = self.handle_ws(msg, ctx) else {
+            return;
         };
 
-        log::debug!("WEBSOCKET MESSAGE: {msg:?}");
         match msg {
-            ws::Message::Ping(msg) => {
-                self.hb = Instant::now();
-                ctx.pong(&msg);
-            }
-            ws::Message::Pong(_) => {
-                self.hb = Instant::now();
+            ws::Message::Text(text) if self.codec == Codec::Json => {
+                match Request::from_json(&text) {
+                    Ok(request) => self.request(request, ctx),
+                    Err(err) => self.error(&format!("invalid message: {err}"), ctx),
+                }
             }
             ws::Message::Text(text) => {
                 let m = text.trim();
                 // we check for /sss type of messages
                 if m.starts_with('/') {
-                    let v: Vec<&str> = m.splitn(2, ' ').collect();
-                    match v[0] {
-                        "/list" => {
-                            // Send ListRooms message to chat server and wait for
-                            // response
-                            println!("List rooms");
-                            self.addr
-                                .send(server::ListRooms)
-                                .into_actor(self)
-                                .then(|res, _, ctx| {
-                                    match res {
-                                        Ok(rooms) => {
-                                            for room in rooms {
-                                                ctx.text(room);
-                                            }
-                                        }
-                                        _ => println!("Something is wrong"),
-                                    }
-                                    fut::ready(())
-                                })
-                                .wait(ctx)
-                            // .wait(ctx) pauses all events in context,
-                            // so actor wont receive any new messages until it get list
-                            // of rooms back
-                        }
-                        "/join" => {
-                            if v.len() == 2 {
-                                self.room = v[1].to_owned();
-                                self.addr.do_send(server::Join {
-                                    id: self.id,
-                                    name: self.room.clone(),
-                                });
-
-                                ctx.text("joined");
-                            } else {
-                                ctx.text("!!! room name is required");
-                            }
-                        }
-                        "/name" => {
-                            if v.len() == 2 {
-                                self.name = Some(v[1].to_owned());
-                            } else {
-                                ctx.text("!!! name is required");
-                            }
-                        }
-                        _ => ctx.text(format!("!!! unknown command: {m:?}")),
-                    }
+                    Self::commands().run(m, self, ctx);
                 } else {
-                    let msg = if let Some(ref name) = self.name {
-                        format!("{name}: {m}")
-                    } else {
-                        m.to_owned()
-                    };
-                    // send message to chat server
-                    self.addr.do_send(server::ClientMessage {
-                        id: self.id,
-                        msg,
-                        room: self.room.clone(),
-                    })
+                    // reuse the frame buffer, no copy
+                    self.chat(text.slice_ref(m))
                 }
             }
-            ws::Message::Binary(_) => println!("Unexpected binary"),
-            ws::Message::Close(reason) => {
-                ctx.close(reason);
-                ctx.stop();
-            }
-            ws::Message::Continuation(_) => {
-                ctx.stop();
-            }
-            ws::Message::Nop => (),
+            ws::Message::Binary(data) if self.codec == Codec::Proto => self.proto_frame(data, ctx),
+            ws::Message::Binary(data) => match self.binary {
+                BinaryMode::Protobuf if self.codec == Codec::Text => {
+                    self.codec = Codec::Proto;
+                    self.proto_frame(data, ctx);
+                }
+                BinaryMode::Relay => self.server().do_send(server::ClientData {
+                    id: self.id(),
+                    data,
+                    room: self.room.clone(),
+                }),
+                _ => log::debug!("binary frame ignored"),
+            },
+            // control frames are handled by `handle_ws`
+            _ => (),
         }
     }
 }
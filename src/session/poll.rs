@@ -0,0 +1,330 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use actix::prelude::*;
+use actix_web::{error::ErrorNotFound, rt::time, web, Error, HttpResponse};
+use rand::Rng;
+use serde::Serialize;
+use tokio::sync::oneshot;
+
+use super::{ChatSession, Registry, Reply, Session, SessionCore};
+use crate::server;
+
+/// How long a poll request waits for messages
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// How long before lack of polling causes a timeout
+const SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a message waits to be polled
+const MESSAGE_TTL: Duration = Duration::from_secs(60);
+
+/// How many messages may wait to be polled, older ones are dropped
+const MAX_QUEUED: usize = 256;
+
+/// Long polling sessions by token
+pub type PollSessions = Mutex<HashMap<String, Addr<PollSession>>>;
+
+/// Message returned by a poll request
+#[derive(Debug, Serialize)]
+pub struct PollMessage {
+    /// Id, time and position in the room history, status messages have
+    /// none
+    #[serde(flatten)]
+    pub meta: Option<server::MessageMeta>,
+
+    pub text: String,
+
+    /// Reaction counts, only replayed messages have them
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<server::ReactionCount>,
+}
+
+/// `POST /poll/connect`, start a session and return its token
+pub async fn connect(
+    srv: web::Data<Addr<server::ChatServer>>,
+    sessions: web::Data<PollSessions>,
+) -> HttpResponse {
+    let mut rng = rand::thread_rng();
+    let token = format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>());
+
+    let addr = PollSession {
+        core: SessionCore::new(srv.get_ref().clone()),
+        token: token.clone(),
+        last_poll: Instant::now(),
+        room: "main".to_owned(),
+        name: None,
+        queue: VecDeque::new(),
+        waiting: None,
+        sessions: sessions.clone(),
+    }
+    .start();
+    sessions.lock().unwrap().insert(token.clone(), addr);
+
+    HttpResponse::Ok().json(serde_json::json!({ "session": token }))
+}
+
+/// `GET /poll/{session}`, wait until messages arrive
+pub async fn poll(
+    token: web::Path<String>,
+    sessions: web::Data<PollSessions>,
+) -> Result<HttpResponse, Error> {
+    let addr = session(&sessions, &token)?;
+    let messages = addr
+        .send(Poll)
+        .await
+        .map_err(|_| ErrorNotFound("session expired"))?;
+
+    Ok(HttpResponse::Ok().json(messages))
+}
+
+/// `POST /poll/{session}/send`, body is handled like a websocket text message
+pub async fn send(
+    token: web::Path<String>,
+    body: String,
+    sessions: web::Data<PollSessions>,
+) -> Result<HttpResponse, Error> {
+    session(&sessions, &token)?.do_send(Line(body));
+    Ok(HttpResponse::Accepted().finish())
+}
+
+fn session(sessions: &PollSessions, token: &str) -> Result<Addr<PollSession>, Error> {
+    sessions
+        .lock()
+        .unwrap()
+        .get(token)
+        .cloned()
+        .ok_or_else(|| ErrorNotFound("unknown session"))
+}
+
+/// Wait for queued messages
+struct Poll;
+
+impl actix::Message for Poll {
+    type Result = Vec<PollMessage>;
+}
+
+/// Text sent by the client
+#[derive(Message)]
+#[rtype(result = "()")]
+struct Line(String);
+
+/// Chat session of a long polling client. Messages are queued until the
+/// client polls them.
+pub struct PollSession {
+    /// Connection with the chat server
+    pub core: SessionCore,
+
+    /// token used in the urls
+    pub token: String,
+
+    /// Client must poll at least once per 60 seconds (SESSION_TIMEOUT),
+    /// otherwise we drop the session.
+    pub last_poll: Instant,
+
+    /// joined room
+    pub room: String,
+
+    /// peer name
+    pub name: Option<String>,
+
+    /// messages waiting to be polled
+    queue: VecDeque<(Instant, PollMessage)>,
+
+    /// poll request waiting for messages
+    waiting: Option<oneshot::Sender<Vec<PollMessage>>>,
+
+    /// All poll sessions, the session removes itself when it stops
+    sessions: web::Data<PollSessions>,
+}
+
+impl PollSession {
+    /// Queue message for the client, a waiting poll request gets it at once
+    fn push(&mut self, meta: Option<server::MessageMeta>, text: impl Into<String>) {
+        self.push_message(PollMessage {
+            meta,
+            text: text.into(),
+            reactions: Vec::new(),
+        });
+    }
+
+    fn push_message(&mut self, msg: PollMessage) {
+        if self.queue.len() == MAX_QUEUED {
+            self.queue.pop_front();
+        }
+        self.queue.push_back((Instant::now(), msg));
+
+        // a timed out poll request leaves a closed sender behind, the
+        // messages stay queued for the next request
+        if let Some(tx) = self.waiting.take().filter(|tx| !tx.is_closed()) {
+            if let Err(messages) = tx.send(self.drain()) {
+                let now = Instant::now();
+                self.queue = messages.into_iter().map(|msg| (now, msg)).collect();
+            }
+        }
+    }
+
+    fn drain(&mut self) -> Vec<PollMessage> {
+        self.queue.drain(..).map(|(_, msg)| msg).collect()
+    }
+
+    /// helper method that checks every 5 seconds if the client still polls
+    /// and drops expired messages
+    fn expire(&self, ctx: &mut Context<Self>) {
+        ctx.run_interval(Duration::from_secs(5), |act, ctx| {
+            // a timed out poll request leaves a closed sender behind
+            let polling = act.waiting.as_ref().is_some_and(|tx| !tx.is_closed());
+            if !polling && Instant::now().duration_since(act.last_poll) > SESSION_TIMEOUT {
+                log::info!("Poll client timed out, disconnecting!");
+                ctx.stop();
+                return;
+            }
+
+            act.queue
+                .retain(|(queued, _)| Instant::now().duration_since(*queued) < MESSAGE_TTL);
+        });
+    }
+}
+
+impl Session for PollSession {
+    fn core(&self) -> &SessionCore {
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut SessionCore {
+        &mut self.core
+    }
+}
+
+impl ChatSession for PollSession {
+    fn room(&self) -> &str {
+        &self.room
+    }
+
+    fn set_room(&mut self, room: &str) {
+        self.room = room.to_owned();
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_owned());
+    }
+
+    fn notice(&mut self, text: &str, _: &mut Self::Context) {
+        self.push(None, text);
+    }
+
+    fn error(&mut self, text: &str, _: &mut Self::Context) {
+        self.push(None, format!("!!! {text}"));
+    }
+
+    fn message(&mut self, stored: &server::StoredMessage, _: &mut Self::Context) {
+        self.push_message(PollMessage {
+            meta: Some(stored.meta.clone()),
+            text: stored.text.to_string(),
+            reactions: stored.reactions(),
+        });
+    }
+
+    fn commands() -> &'static Registry<Self> {
+        static COMMANDS: OnceLock<Registry<PollSession>> = OnceLock::new();
+        COMMANDS.get_or_init(Registry::new)
+    }
+}
+
+impl Actor for PollSession {
+    type Context = Context<Self>;
+
+    /// Method is called on actor start.
+    /// We register poll session with ChatServer
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.expire(ctx);
+
+        self.register(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.unregister();
+        self.sessions.lock().unwrap().remove(&self.token);
+        Running::Stop
+    }
+}
+
+/// Handle messages from chat server, they wait for the next poll
+impl Handler<server::Message> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) {
+        match msg.payload {
+            server::Payload::Text(ref text) => self.push(msg.meta.clone(), text.to_string()),
+            // room events are polled as status lines
+            server::Payload::Event(ref event) => self.push(None, Reply::from(event).line()),
+            server::Payload::Binary(_) => (),
+        }
+
+        if msg.overflowed() {
+            ctx.stop();
+        }
+    }
+}
+
+/// Handler for Poll message.
+///
+/// Queued messages are returned at once, otherwise the request waits for
+/// new messages until `POLL_TIMEOUT`.
+impl Handler<Poll> for PollSession {
+    type Result = ResponseFuture<Vec<PollMessage>>;
+
+    fn handle(&mut self, _: Poll, _: &mut Self::Context) -> Self::Result {
+        self.last_poll = Instant::now();
+
+        if !self.queue.is_empty() {
+            let messages = self.drain();
+            return Box::pin(async move { messages });
+        }
+
+        let (tx, rx) = oneshot::channel();
+        // an older request gets an empty answer
+        self.waiting = Some(tx);
+
+        Box::pin(async move {
+            match time::timeout(POLL_TIMEOUT, rx).await {
+                Ok(Ok(messages)) => messages,
+                _ => Vec::new(),
+            }
+        })
+    }
+}
+
+/// Handler for text sent by the client
+impl Handler<Line> for PollSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Line, ctx: &mut Self::Context) {
+        self.last_poll = Instant::now();
+
+        let m = msg.0.trim();
+        // we check for /sss type of messages
+        if m.starts_with('/') {
+            Self::commands().run(m, self, ctx);
+        } else if !m.is_empty() {
+            let msg = if let Some(ref name) = self.name {
+                format!("{name}: {m}")
+            } else {
+                m.to_owned()
+            };
+            // send message to chat server
+            self.server().do_send(server::ClientMessage {
+                id: self.id(),
+                msg: msg.into(),
+                room: self.room.clone(),
+            })
+        }
+    }
+}
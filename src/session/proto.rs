@@ -0,0 +1,445 @@
+//! Protobuf messages of the binary protocol, kept in sync with
+//! `proto/chat.proto` by hand.
+
+use bytes::Bytes;
+use chrono::{DateTime, Utc};
+use prost::Message;
+
+use crate::server::{self, MessageMeta};
+
+/// Sent by the client, same as the text commands
+#[derive(Clone, PartialEq, Message)]
+pub struct ClientFrame {
+    #[prost(
+        oneof = "client_frame::Frame",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17"
+    )]
+    pub frame: Option<client_frame::Frame>,
+}
+
+pub mod client_frame {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Frame {
+        /// some message
+        #[prost(string, tag = "1")]
+        Text(String),
+
+        /// `/list`
+        #[prost(message, tag = "2")]
+        List(super::ListRooms),
+
+        /// `/join name`
+        #[prost(string, tag = "3")]
+        Join(String),
+
+        /// `/name name`
+        #[prost(string, tag = "4")]
+        Name(String),
+
+        /// `/typing start|stop`
+        #[prost(bool, tag = "5")]
+        Typing(bool),
+
+        /// `/sync seq`
+        #[prost(uint64, tag = "6")]
+        Sync(u64),
+
+        /// `/edit id text`
+        #[prost(message, tag = "7")]
+        Edit(super::EditMessage),
+
+        /// `/delete id`
+        #[prost(uint64, tag = "8")]
+        Delete(u64),
+
+        /// `/mod token`
+        #[prost(string, tag = "9")]
+        Mod(String),
+
+        /// `/react id emoji`
+        #[prost(message, tag = "10")]
+        React(super::Reaction),
+
+        /// `/unreact id emoji`
+        #[prost(message, tag = "11")]
+        Unreact(super::Reaction),
+
+        /// `/reply id text`
+        #[prost(message, tag = "12")]
+        Reply(super::ThreadReply),
+
+        /// `/thread id`
+        #[prost(uint64, tag = "13")]
+        Thread(u64),
+
+        /// `/follow id`
+        #[prost(uint64, tag = "14")]
+        Follow(u64),
+
+        /// `/unfollow id`
+        #[prost(uint64, tag = "15")]
+        Unfollow(u64),
+
+        /// `/search query`
+        #[prost(string, tag = "16")]
+        Search(String),
+
+        /// `/resume token`
+        #[prost(string, tag = "17")]
+        Resume(String),
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ListRooms {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct Reaction {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+
+    #[prost(string, tag = "2")]
+    pub emoji: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ThreadReply {
+    #[prost(uint64, tag = "1")]
+    pub parent: u64,
+
+    #[prost(string, tag = "2")]
+    pub text: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct EditMessage {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+
+    #[prost(string, tag = "2")]
+    pub text: String,
+}
+
+/// Sent by the server
+#[derive(Clone, PartialEq, Message)]
+pub struct ServerFrame {
+    #[prost(
+        oneof = "server_frame::Frame",
+        tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12"
+    )]
+    pub frame: Option<server_frame::Frame>,
+}
+
+pub mod server_frame {
+    #[derive(Clone, PartialEq, prost::Oneof)]
+    pub enum Frame {
+        #[prost(message, tag = "1")]
+        Message(super::ChatMessage),
+
+        /// status message
+        #[prost(string, tag = "2")]
+        Notice(String),
+
+        #[prost(message, tag = "3")]
+        Rooms(super::RoomList),
+
+        /// invalid command
+        #[prost(string, tag = "4")]
+        Error(String),
+
+        #[prost(message, tag = "5")]
+        Typing(super::TypingIndicator),
+
+        #[prost(message, tag = "6")]
+        CaughtUp(super::CaughtUp),
+
+        #[prost(message, tag = "7")]
+        Edited(super::MessageEdited),
+
+        #[prost(message, tag = "8")]
+        Deleted(super::MessageDeleted),
+
+        #[prost(message, tag = "9")]
+        Reaction(super::ReactionChanged),
+
+        #[prost(message, tag = "10")]
+        Thread(super::ThreadNotification),
+
+        #[prost(message, tag = "11")]
+        Mention(super::Mention),
+
+        #[prost(message, tag = "12")]
+        Sent(super::MessageSent),
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ChatMessage {
+    #[prost(string, tag = "1")]
+    pub text: String,
+
+    /// position in the room history
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+
+    /// unique message id
+    #[prost(uint64, tag = "3")]
+    pub id: u64,
+
+    /// server time, milliseconds since the unix epoch
+    #[prost(int64, tag = "4")]
+    pub time_ms: i64,
+
+    /// last edit, 0 when the message was not edited
+    #[prost(int64, tag = "5")]
+    pub edited_ms: i64,
+
+    /// only replayed messages have reactions
+    #[prost(message, repeated, tag = "6")]
+    pub reactions: Vec<ReactionCount>,
+
+    /// first message of the thread, 0 when the message is no reply
+    #[prost(uint64, tag = "7")]
+    pub thread: u64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ReactionCount {
+    #[prost(string, tag = "1")]
+    pub emoji: String,
+
+    #[prost(uint64, tag = "2")]
+    pub count: u64,
+}
+
+/// Reaction was added or removed
+#[derive(Clone, PartialEq, Message)]
+pub struct ReactionChanged {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+
+    #[prost(string, tag = "3")]
+    pub emoji: String,
+
+    /// who reacted
+    #[prost(string, tag = "4")]
+    pub name: String,
+
+    #[prost(bool, tag = "5")]
+    pub added: bool,
+
+    /// new number of reactions with the emoji
+    #[prost(uint64, tag = "6")]
+    pub count: u64,
+}
+
+/// Reply in a followed thread of another room
+#[derive(Clone, PartialEq, Message)]
+pub struct ThreadNotification {
+    #[prost(string, tag = "1")]
+    pub room: String,
+
+    #[prost(uint64, tag = "2")]
+    pub thread: u64,
+
+    #[prost(uint64, tag = "3")]
+    pub id: u64,
+
+    #[prost(string, tag = "4")]
+    pub text: String,
+}
+
+/// Message that mentions the client
+#[derive(Clone, PartialEq, Message)]
+pub struct Mention {
+    #[prost(string, tag = "1")]
+    pub room: String,
+
+    #[prost(uint64, tag = "2")]
+    pub id: u64,
+
+    /// author
+    #[prost(string, tag = "3")]
+    pub by: String,
+
+    #[prost(string, tag = "4")]
+    pub text: String,
+
+    #[prost(int64, tag = "5")]
+    pub time_ms: i64,
+}
+
+/// Author changed the text of a message
+#[derive(Clone, PartialEq, Message)]
+pub struct MessageEdited {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+
+    #[prost(string, tag = "3")]
+    pub text: String,
+
+    #[prost(int64, tag = "4")]
+    pub time_ms: i64,
+}
+
+/// Message was deleted
+#[derive(Clone, PartialEq, Message)]
+pub struct MessageDeleted {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+}
+
+/// Message of the client was accepted
+#[derive(Clone, PartialEq, Message)]
+pub struct MessageSent {
+    #[prost(uint64, tag = "1")]
+    pub id: u64,
+
+    #[prost(uint64, tag = "2")]
+    pub seq: u64,
+
+    #[prost(int64, tag = "3")]
+    pub time_ms: i64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct RoomList {
+    #[prost(string, repeated, tag = "1")]
+    pub rooms: Vec<String>,
+}
+
+/// Room member started or stopped typing
+#[derive(Clone, PartialEq, Message)]
+pub struct TypingIndicator {
+    #[prost(string, tag = "1")]
+    pub name: String,
+
+    #[prost(bool, tag = "2")]
+    pub active: bool,
+}
+
+/// History replay reached the latest message of the room
+#[derive(Clone, PartialEq, Message)]
+pub struct CaughtUp {
+    #[prost(uint64, tag = "1")]
+    pub seq: u64,
+
+    /// false when some messages already left the history
+    #[prost(bool, tag = "2")]
+    pub complete: bool,
+}
+
+impl ServerFrame {
+    fn encode(frame: server_frame::Frame) -> Bytes {
+        ServerFrame { frame: Some(frame) }.encode_to_vec().into()
+    }
+
+    /// Room message
+    pub fn message(text: &str, meta: &MessageMeta, reactions: &[server::ReactionCount]) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Message(ChatMessage {
+            text: text.to_owned(),
+            seq: meta.seq,
+            id: meta.id,
+            time_ms: meta.time.timestamp_millis(),
+            edited_ms: meta.edited.map_or(0, |time| time.timestamp_millis()),
+            reactions: reactions
+                .iter()
+                .map(|reaction| ReactionCount {
+                    emoji: reaction.emoji.clone(),
+                    count: reaction.count as u64,
+                })
+                .collect(),
+            thread: meta.thread.unwrap_or(0),
+        }))
+    }
+
+    pub fn notice(text: &str) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Notice(text.to_owned()))
+    }
+
+    pub fn rooms(rooms: Vec<String>) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Rooms(RoomList { rooms }))
+    }
+
+    pub fn typing(name: &str, active: bool) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Typing(TypingIndicator {
+            name: name.to_owned(),
+            active,
+        }))
+    }
+
+    pub fn caught_up(seq: u64, complete: bool) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::CaughtUp(CaughtUp { seq, complete }))
+    }
+
+    pub fn edited(id: u64, seq: u64, text: &str, time: &DateTime<Utc>) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Edited(MessageEdited {
+            id,
+            seq,
+            text: text.to_owned(),
+            time_ms: time.timestamp_millis(),
+        }))
+    }
+
+    pub fn deleted(id: u64, seq: u64) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Deleted(MessageDeleted { id, seq }))
+    }
+
+    pub fn sent(id: u64, seq: u64, time: &DateTime<Utc>) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Sent(MessageSent {
+            id,
+            seq,
+            time_ms: time.timestamp_millis(),
+        }))
+    }
+
+    pub fn reaction(
+        id: u64,
+        seq: u64,
+        emoji: &str,
+        name: &str,
+        added: bool,
+        count: usize,
+    ) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Reaction(ReactionChanged {
+            id,
+            seq,
+            emoji: emoji.to_owned(),
+            name: name.to_owned(),
+            added,
+            count: count as u64,
+        }))
+    }
+
+    pub fn thread(room: &str, thread: u64, id: u64, text: &str) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Thread(ThreadNotification {
+            room: room.to_owned(),
+            thread,
+            id,
+            text: text.to_owned(),
+        }))
+    }
+
+    pub fn mention(room: &str, id: u64, by: &str, text: &str, time: &DateTime<Utc>) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Mention(Mention {
+            room: room.to_owned(),
+            id,
+            by: by.to_owned(),
+            text: text.to_owned(),
+            time_ms: time.timestamp_millis(),
+        }))
+    }
+
+    pub fn error(text: &str) -> Bytes {
+        ServerFrame::encode(server_frame::Frame::Error(text.to_owned()))
+    }
+}
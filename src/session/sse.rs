@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use actix::prelude::*;
+use actix_web::{
+    error::{ErrorInternalServerError, ErrorNotFound},
+    web, Error, HttpRequest, Responder,
+};
+use actix_web_lab::sse;
+
+use crate::server;
+
+/// How many events may wait for the http response
+const CHANNEL_SIZE: usize = 16;
+
+/// How often the client is pinged, a closed stream stops the subscriber.
+/// The ping is also the keep-alive of the response.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Read-only stream of room messages as Server-Sent Events.
+///
+/// Event id is the sequence number of the message in the room, so a client
+/// reconnecting with `Last-Event-ID` gets the messages it missed from the
+/// room history. Unknown rooms are `404 Not Found`.
+pub async fn room_events(
+    req: HttpRequest,
+    room: web::Path<String>,
+    srv: web::Data<Addr<server::ChatServer>>,
+) -> Result<impl Responder, Error> {
+    let since = req
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok());
+
+    // subscribe before the response starts, so unknown rooms can be refused
+    let ctx = Context::new();
+    let id = srv
+        .send(server::Subscribe {
+            addr: ctx.address().recipient(),
+            room: room.clone(),
+            since,
+        })
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("unknown room"))?;
+
+    let (tx, stream) = sse::channel(CHANNEL_SIZE);
+
+    ctx.run(SseSession {
+        id,
+        room: room.into_inner(),
+        tx,
+        addr: srv.get_ref().clone(),
+    });
+
+    Ok(stream)
+}
+
+/// Adapter between `ChatServer` and a Server-Sent Events response
+pub struct SseSession {
+    /// unique session id
+    pub id: usize,
+
+    /// watched room
+    pub room: String,
+
+    /// Sending half of the event stream
+    pub tx: sse::Sender,
+
+    /// Chat server
+    pub addr: Addr<server::ChatServer>,
+}
+
+impl Actor for SseSession {
+    type Context = Context<Self>;
+
+    /// Method is called on actor start, `room_events` already subscribed
+    /// to the room
+    fn started(&mut self, ctx: &mut Self::Context) {
+        // keep the response alive and stop once the client went away
+        ctx.run_interval(KEEP_ALIVE_INTERVAL, |act, ctx| {
+            if let Err(sse::TrySendError::Closed(_)) =
+                act.tx.try_send(sse::Event::Comment("ping".into()))
+            {
+                ctx.stop();
+            }
+        });
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        // notify chat server
+        self.addr.do_send(server::Disconnect { id: self.id });
+        Running::Stop
+    }
+}
+
+/// Handle messages from chat server, they are sent as events.
+///
+/// The message is kept until the event is queued, so the outbox holds
+/// back further messages while the client is slow.
+impl Handler<server::Message> for SseSession {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: server::Message, _: &mut Self::Context) -> Self::Result {
+        // watchers only receive text
+        let server::Payload::Text(ref text) = msg.payload else {
+            return Box::pin(fut::ready(()));
+        };
+
+        let mut data = sse::Data::new(text.clone());
+        if let Some(meta) = &msg.meta {
+            data.set_id(meta.seq.to_string());
+        }
+
+        let tx = self.tx.clone();
+        Box::pin(
+            async move {
+                let res = tx.send(data).await;
+                (res, msg.overflowed())
+            }
+            .into_actor(self)
+            .map(|(res, overflowed), _, ctx| {
+                if res.is_err() || overflowed {
+                    ctx.stop();
+                }
+            }),
+        )
+    }
+}
@@ -0,0 +1,237 @@
+use std::{io, sync::OnceLock, time::Duration};
+
+use actix::prelude::*;
+use actix_codec::LinesCodec;
+use bytestring::ByteString;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_util::codec::FramedRead;
+
+use super::{lines::LineWriter, Args, ChatSession, Command, Registry, Reply, Session, SessionCore};
+use crate::server;
+
+/// Longest line a client may send
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+
+/// Idle time before the kernel probes a connection, line clients do not
+/// answer pings so dead peers are found with tcp keepalive
+const KEEPALIVE_TIME: Duration = Duration::from_secs(10);
+
+/// Time between unanswered keepalive probes
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Accept plain tcp connections, every line is handled like a websocket
+/// text message
+pub async fn serve(addr: &str, srv: Addr<server::ChatServer>) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("starting TCP listener at {addr}");
+
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        log::debug!("tcp client connected from {peer}");
+
+        let keepalive = TcpKeepalive::new()
+            .with_time(KEEPALIVE_TIME)
+            .with_interval(KEEPALIVE_INTERVAL);
+        if let Err(err) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+            log::warn!("can not enable keepalive for {peer}: {err}");
+        }
+
+        TcpChatSession::start_with(stream, srv.clone());
+    }
+}
+
+pub struct TcpChatSession {
+    /// Connection with the chat server
+    pub core: SessionCore,
+
+    /// joined room
+    pub room: String,
+
+    /// peer name
+    pub name: Option<String>,
+
+    /// Write half of the connection
+    writer: LineWriter,
+}
+
+impl TcpChatSession {
+    fn start_with(stream: TcpStream, addr: Addr<server::ChatServer>) -> Addr<Self> {
+        let (read, write) = stream.into_split();
+
+        TcpChatSession::create(move |ctx| {
+            ctx.add_stream(FramedRead::new(
+                read,
+                LinesCodec::new_with_max_length(MAX_LINE_LENGTH),
+            ));
+
+            TcpChatSession {
+                core: SessionCore::new(addr),
+                room: "main".to_owned(),
+                name: None,
+                writer: LineWriter::new(write),
+            }
+        })
+    }
+
+    fn text(&mut self, text: impl Into<ByteString>) {
+        self.writer.write(text);
+    }
+}
+
+impl Session for TcpChatSession {
+    fn core(&self) -> &SessionCore {
+        &self.core
+    }
+
+    fn core_mut(&mut self) -> &mut SessionCore {
+        &mut self.core
+    }
+}
+
+impl ChatSession for TcpChatSession {
+    fn room(&self) -> &str {
+        &self.room
+    }
+
+    fn set_room(&mut self, room: &str) {
+        self.room = room.to_owned();
+    }
+
+    fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_owned());
+    }
+
+    fn notice(&mut self, text: &str, _: &mut Self::Context) {
+        self.text(text.to_owned());
+    }
+
+    fn error(&mut self, text: &str, _: &mut Self::Context) {
+        self.text(format!("!!! {text}"));
+    }
+
+    fn commands() -> &'static Registry<Self> {
+        static COMMANDS: OnceLock<Registry<TcpChatSession>> = OnceLock::new();
+        COMMANDS.get_or_init(|| Registry::new().with(Quit))
+    }
+}
+
+/// `/quit`, line clients can not close the connection otherwise
+struct Quit;
+
+impl Command<TcpChatSession> for Quit {
+    fn name(&self) -> &'static str {
+        "quit"
+    }
+
+    fn help(&self) -> &'static str {
+        "close the connection"
+    }
+
+    fn run(&self, _: Args<'_>, session: &mut TcpChatSession, ctx: &mut Context<TcpChatSession>) {
+        // pending lines are written first, then the connection is closed
+        session.writer.close();
+        ctx.stop();
+    }
+}
+
+impl Actor for TcpChatSession {
+    type Context = Context<Self>;
+
+    /// Method is called on actor start.
+    /// We register tcp session with ChatServer
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.register(ctx);
+    }
+
+    fn stopping(&mut self, _: &mut Self::Context) -> Running {
+        self.unregister();
+        Running::Stop
+    }
+}
+
+/// Handle messages from chat server, we simply send it to peer. The next
+/// message is released when this one is queued for writing.
+impl Handler<server::Message> for TcpChatSession {
+    type Result = ResponseActFuture<Self, ()>;
+
+    fn handle(&mut self, msg: server::Message, ctx: &mut Self::Context) -> Self::Result {
+        if msg.overflowed() {
+            // peer can not keep up with the room
+            self.writer.close();
+            ctx.stop();
+            return Box::pin(fut::ready(()));
+        }
+
+        let receipt = msg.receipt;
+        // same lines as websocket text clients, binary data can not be shown
+        let line = match (msg.payload, msg.meta) {
+            (server::Payload::Text(text), Some(meta)) => {
+                Some(format!("[{}] {text}", meta.id).into())
+            }
+            (server::Payload::Text(text), None) => Some(text),
+            (server::Payload::Event(event), _) => Some(Reply::from(&event).line()),
+            (server::Payload::Binary(_), _) => None,
+        };
+        let send = line.map(|line| self.writer.send(line));
+
+        Box::pin(
+            async move {
+                let written = match send {
+                    Some(send) => send.await.is_ok(),
+                    None => true,
+                };
+                drop(receipt);
+                written
+            }
+            .into_actor(self)
+            .map(|written, _, ctx| {
+                if !written {
+                    // peer is gone
+                    ctx.stop();
+                }
+            }),
+        )
+    }
+}
+
+/// Line handler
+impl StreamHandler<Result<String, io::Error>> for TcpChatSession {
+    fn handle(&mut self, line: Result<String, io::Error>, ctx: &mut Self::Context) {
+        let line = match line {
+            Ok(line) => line,
+            Err(err) => {
+                log::debug!("tcp client error: {err}");
+                ctx.stop();
+                return;
+            }
+        };
+
+        // the codec already removed the line terminator, the rest is content
+        let m = line.as_str();
+        if m.is_empty() {
+            return;
+        }
+
+        // we check for /sss type of messages
+        if m.starts_with('/') {
+            Self::commands().run(m, self, ctx);
+        } else {
+            let msg = if let Some(ref name) = self.name {
+                format!("{name}: {m}")
+            } else {
+                m.to_owned()
+            };
+            // send message to chat server
+            self.server().do_send(server::ClientMessage {
+                id: self.id(),
+                msg: msg.into(),
+                room: self.room.clone(),
+            })
+        }
+    }
+}